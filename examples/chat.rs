@@ -1,183 +1,179 @@
-use core::fmt;
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
-use dashmap::DashMap;
-use futures::{stream::SplitStream, SinkExt, StreamExt};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc::{channel, Sender},
-};
-use tokio_util::codec::{Framed, LinesCodec};
+use chat::chat::{handle_connection, ChatConfig, ChatState};
+use clap::Parser;
+use tokio::{io::AsyncWriteExt, net::TcpListener, signal, task::JoinSet};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
-const MSG_SIZE: usize = 1024;
+const ADDR: &str = "127.0.0.1:8080";
+const MAX_CONNECTIONS: usize = 1024;
 
-#[derive(Debug)]
-struct ChatState {
-    peers: DashMap<SocketAddr, Sender<Arc<Message>>>,
-}
-
-#[derive(Debug)]
-enum Message {
-    Join(String),
-    Left(String),
-    Text { user: String, content: String },
+// 启动参数，命令行优先，其次是环境变量，最后是默认值
+#[derive(Debug, Parser)]
+struct Cli {
+    // 监听地址，端口为0时由系统分配
+    #[arg(long, env = "CHAT_ADDR", default_value = ADDR)]
+    addr: SocketAddr,
+    // 同时处理的连接数上限，达到上限后新连接直接被拒绝；每个连接另有一个写任务，随连接结束
+    #[arg(long, env = "CHAT_MAX_CONNECTIONS", default_value_t = MAX_CONNECTIONS)]
+    max_connections: usize,
 }
 
-#[derive(Debug)]
-struct Peer {
-    username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
-}
 #[tokio::main]
 async fn main() -> Result<()> {
     let layer = Layer::new().with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
 
-    let addr = "127.0.0.1:8080";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on {}", addr);
+    let cli = Cli::parse();
+    let listener = TcpListener::bind(cli.addr).await?;
+    info!("Listening on {}", listener.local_addr()?);
+
+    let state = Arc::new(ChatState::new(ChatConfig::from_env()));
+    serve(
+        listener,
+        Arc::clone(&state),
+        cli.max_connections,
+        shutdown_signal(),
+    )
+    .await?;
+
+    state.shutdown().await;
+    Ok(())
+}
 
-    let state = Arc::new(ChatState::new());
+// 接受连接直到shutdown完成，同时处理的连接数达到max_connections时拒绝新连接
+async fn serve(
+    listener: TcpListener,
+    state: Arc<ChatState>,
+    max_connections: usize,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    // 跟踪所有连接任务，已结束的任务在循环中回收
+    let mut connections = JoinSet::new();
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (mut stream, addr) = tokio::select! {
+            ret = listener.accept() => ret?,
+            Some(ret) = connections.join_next() => {
+                if let Err(e) = ret {
+                    warn!("Connection task error:{}", e);
+                }
+                continue;
+            }
+            // 收到退出信号后不再接受新连接
+            _ = &mut shutdown => break,
+        };
+        if connections.len() >= max_connections {
+            warn!("Too many connections, rejected {}", addr);
+            if let Err(e) = stream.write_all(b"Server busy, try again later\n").await {
+                warn!("Error rejecting {}: {}", addr, e);
+            }
+            continue;
+        }
         let state = Arc::clone(&state);
         info!("New connection from {}", addr);
-        tokio::spawn(async move {
+        connections.spawn(async move {
             if let Err(e) = handle_connection(stream, addr, state).await {
                 warn!("Error handling connection from {}: {}", addr, e);
             };
             info!("Connection closed for {}", addr);
         });
     }
-
-    #[allow(unreachable_code)]
     Ok(())
 }
-async fn handle_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
-    state: Arc<ChatState>,
-) -> Result<()> {
-    // 将TcpStream使用LinesCodec封装成Framed对象，按行进行数据分割
-    let mut stream = Framed::new(stream, LinesCodec::new());
-
-    // send方法是由futures这个crate的SinkExt  trait实现的，可以异步地将数据发送到流中
-    stream.send("Enter your name:").await?;
-    // next方法返回Option<Result<>>，本来可以使用？？进行错误传播的，但是这个handler的返回类型为Result
-    let username = match stream.next().await {
-        Some(option) => match option {
-            Ok(name) => name,
-            Err(e) => {
-                warn!("Username codec error:{}", e.to_string());
-                return Ok(());
-            }
-        },
-        None => {
-            warn!("No username provided");
-            return Ok(());
+
+// 等待Ctrl+C或SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C:{}", e);
+            std::future::pending::<()>().await;
         }
     };
-
-    // 将 用户的信息--sender stream 关联，开启异步task当broadcast时使用sender stream向每个用户client发送消息
-    let mut peer = state.add_peer(addr, username, stream);
-    let msg = Arc::new(Message::user_join(&peer.username));
-    // 广播用户的到来
-    state.broadcast(msg, addr).await?;
-
-    while let Some(line) = peer.stream.next().await {
-        match line {
-            Ok(line) => {
-                let msg = Arc::new(Message::new_text(&peer.username, line));
-                state.broadcast(msg, addr).await?;
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
             }
             Err(e) => {
-                warn!("Error reading line from stream: {}", e);
-                break;
+                warn!("Failed to listen for SIGTERM:{}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
-
-    // 用户退出chat
-    let msg = Arc::new(Message::user_left(&peer.username));
-    state.broadcast(msg, addr).await?;
-    info!("user left:{}", peer.username);
-    state.peers.remove(&addr);
-
-    Ok(())
+    info!("Shutdown signal received");
 }
 
-impl ChatState {
-    fn new() -> Self {
-        Self {
-            peers: DashMap::new(),
-        }
-    }
-    async fn broadcast(&self, msg: Arc<Message>, addr: SocketAddr) -> Result<()> {
-        for peer in self.peers.iter() {
-            if peer.key() == &addr {
-                continue;
-            }
-            if let Err(e) = peer.value().send(msg.clone()).await {
-                warn!("Error sending message to {}: {}", peer.key(), e);
-                self.peers.remove(peer.key());
-            };
-        }
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpStream,
+        sync::oneshot,
+        time,
+    };
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // 连接后读取服务端发来的第一行
+    async fn first_line(addr: SocketAddr) -> (String, BufReader<TcpStream>) {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        time::timeout(RECV_TIMEOUT, stream.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+        (line, stream)
     }
-    fn add_peer(
-        &self,
-        addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
-        let (tx, mut rx) = channel::<Arc<Message>>(MSG_SIZE);
-        self.peers.insert(addr, tx);
-
-        let (mut sender, receiver) = stream.split();
-        tokio::spawn(async move {
-            // rx extract数据之后要修改自身内部状态，所以是mutable
-            while let Some(msg) = rx.recv().await {
-                let content = format!("{}", msg);
-                if let Err(e) = sender.send(content).await {
-                    warn!("Error sending message to {}: {}", addr, e);
-                }
+
+    #[tokio::test]
+    async fn connections_beyond_the_ceiling_are_rejected_until_one_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ChatState::new(ChatConfig::default()));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, state, 2, async {
+            let _ = stopped.await;
+        }));
+
+        let (line, first) = first_line(addr).await;
+        assert!(line.contains("Enter your name:"), "{}", line);
+        let (line, _second) = first_line(addr).await;
+        assert!(line.contains("Enter your name:"), "{}", line);
+        let (line, mut rejected) = first_line(addr).await;
+        assert_eq!(line, "Server busy, try again later\n");
+        let mut rest = String::new();
+        rejected.read_line(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "{}", rest);
+
+        // 断开的连接任务被回收后可以接受新连接
+        drop(first);
+        let deadline = time::Instant::now() + RECV_TIMEOUT;
+        loop {
+            let (line, _) = first_line(addr).await;
+            if line.contains("Enter your name:") {
+                break;
             }
-        });
-        // receiver是SplitStream，可以异步地接收数据
-        // 不需要mut是因为它是一个异步迭代器，不需要主动修改内部状态
-        Peer {
-            username,
-            stream: receiver,
-        }
-    }
-}
-impl Message {
-    fn user_join(username: &str) -> Self {
-        let username = username.to_string();
-        Message::Join(username)
-    }
-    fn user_left(username: &str) -> Self {
-        let username = username.to_string();
-        Message::Left(username)
-    }
-    fn new_text(username: &str, content: String) -> Self {
-        let username = username.to_string();
-        Message::Text {
-            user: username,
-            content,
+            assert!(time::Instant::now() < deadline, "slot was not freed");
+            time::sleep(Duration::from_millis(10)).await;
         }
-    }
-}
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Message::Join(name) => write!(f, "[{} JOINED]", name),
-            Message::Left(name) => write!(f, "[{} LEFT]", name),
-            Message::Text { user, content } => write!(f, "[{}]:{}", user, content),
-        }
+        stop.send(()).unwrap();
+        time::timeout(RECV_TIMEOUT, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
     }
 }
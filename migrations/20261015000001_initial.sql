@@ -0,0 +1,34 @@
+-- 初始表结构；使用if not exists，兼容迁移引入之前由启动代码建好的库
+create table if not exists urls(
+    id varchar(32) primary key,
+    url text unique not null
+);
+alter table urls add column if not exists disabled boolean not null default false;
+alter table urls add column if not exists expires_at timestamptz;
+alter table urls add column if not exists clicks bigint not null default 0;
+-- 旧表的id为char(6)，放宽以容纳自定义别名
+alter table urls alter column id type varchar(32);
+
+-- A/B分流短链的各个目标
+create table if not exists variants(
+    id varchar(32) not null,
+    url text not null,
+    weight integer not null,
+    clicks bigint not null default 0,
+    primary key(id, url)
+);
+
+-- 已审核过的目标域名
+create table if not exists reviewed_domains(
+    host text primary key
+);
+
+-- 每次跳转的访问日志
+create table if not exists clicks(
+    id varchar(32) not null,
+    clicked_at timestamptz not null default now(),
+    ip text not null,
+    user_agent text,
+    referer text
+);
+alter table clicks alter column id type varchar(32);
@@ -0,0 +1,2 @@
+-- 短链创建时间，已有记录取执行迁移的时间
+alter table urls add column if not exists created_at timestamptz not null default now();
@@ -0,0 +1,4107 @@
+use core::fmt;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use flate2::{write::GzEncoder, Compression};
+use futures::{stream::BoxStream, Sink, SinkExt, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::{
+        mpsc::{channel, error::TrySendError, Receiver, Sender},
+        Mutex, Notify, RwLock,
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tracing::{info, warn};
+use url::Url;
+
+const MSG_SIZE: usize = 1024;
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_NAME_LEN: usize = 32;
+const RESERVED_NAMES: [&str; 3] = ["admin", "server", "system"];
+const HISTORY_SIZE: usize = 50;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// 发送队列剩余容量低于该值时视为客户端消费过慢
+const BACKPRESSURE_THRESHOLD: usize = MSG_SIZE / 10;
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_ROOM: &str = "lobby";
+const MAX_PASSWORD_ATTEMPTS: usize = 3;
+// 勿扰模式下每个会话最多暂存的私信数
+const DND_QUEUE_SIZE: usize = 50;
+// 关闭时等待发送队列清空的最长时间
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+// 连续这么多个心跳周期没有收到任何数据时断开
+const HEARTBEAT_MISSES: u32 = 3;
+// 单个代码块的最大行数和字节数
+const MAX_CODE_LINES: usize = 100;
+const MAX_CODE_BYTES: usize = 8 * 1024;
+// 导出的聊天记录文件最大字节数
+const MAX_EXPORT_BYTES: usize = 1024 * 1024;
+// 两次/export之间的最短间隔
+const EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+// 连续超限这么多次后临时禁言
+const MUTE_AFTER_VIOLATIONS: u32 = 5;
+const MUTE_DURATION: Duration = Duration::from_secs(30);
+const WEBHOOK_QUEUE: usize = 1024;
+const WEBHOOK_RETRIES: u32 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const WEBHOOK_BACKOFF: Duration = Duration::from_millis(500);
+const WEBHOOK_SIGNATURE_HEADER: &str = "x-chat-signature";
+const TRANSCRIPT_QUEUE: usize = 1024;
+const TRANSCRIPT_FLUSH: Duration = Duration::from_secs(1);
+const QUOTA_REACHED: &str = "session message limit reached";
+// /share时HEAD请求目标地址的时限和最多跟随的重定向次数
+const SHARE_HEAD_TIMEOUT: Duration = Duration::from_secs(3);
+const SHARE_MAX_REDIRECTS: usize = 3;
+
+// 消息中控制字符的处理方式：Strip剔除后继续广播，Reject整条拒绝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeMode {
+    Strip,
+    Reject,
+}
+
+// 发给客户端的消息格式：Json每行一个JSON对象，Text为便于telnet阅读的纯文本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Json,
+    Text,
+}
+
+#[derive(Debug)]
+pub struct ChatConfig {
+    pub protocol: Protocol,
+    pub sanitize: SanitizeMode,
+    // 每个连接保留的最近消息条数，为0时关闭/last
+    pub last_size: usize,
+    // 每个连接最多可发送的消息数，None表示不限制
+    pub session_quota: Option<usize>,
+    // 新连接默认回显自己的消息，可通过/echo或CHAT_ECHO=0关闭
+    pub echo: bool,
+    // 用户名到角色的映射，使用这些用户名登录需要输入角色密码
+    pub roles: HashMap<String, Role>,
+    // 是否在发送给客户端的文本中带上消息序号
+    pub show_seq: bool,
+    // 是否要求用户名唯一
+    pub unique_names: bool,
+    // 允许重名时，同一用户名的最大并发连接数，None表示不限制
+    pub max_sessions: Option<usize>,
+    // 连接在该时长内没有发送任何内容则断开
+    pub idle_timeout: Duration,
+    // 心跳间隔，None表示不发送心跳
+    pub heartbeat: Option<Duration>,
+    // 加入聊天需要输入的共享密码，None表示不需要
+    pub join_password: Option<String>,
+    // 通过/auth获得/kick、/ban、/whois、/export、/say和/rename-room权限的管理员token，None表示不开启
+    pub admin_token: Option<String>,
+    // /export导出文件的保存目录
+    pub export_dir: PathBuf,
+    // 每个连接的发送频率上限，None表示不限制
+    pub rate_limit: Option<RateLimit>,
+    // 聊天事件的webhook，None表示不发送
+    pub webhook: Option<WebhookConfig>,
+    // 聊天记录按天写入该目录下的chat-YYYY-MM-DD.log，None表示不记录
+    pub transcript_dir: Option<PathBuf>,
+    // 聊天记录中是否包含加入/离开
+    pub transcript_joins: bool,
+    // 之前日期的聊天记录压缩为.log.gz后最多保留的个数，None表示全部保留
+    pub transcript_keep: Option<usize>,
+    // 禁止使用的用户名，统一小写，不区分大小写匹配；角色徽章名也不能用作用户名
+    pub blocked_names: HashSet<String>,
+    // /share时是否HEAD请求目标地址，在分享通知中附带文件类型和大小
+    pub share_head: bool,
+}
+
+// 角色徽章，例如MOD；密码证明登录者确实是该用户
+#[derive(Debug, Clone)]
+pub struct Role {
+    pub badge: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    // 配置后请求带上 x-chat-signature: sha256=<请求体的HMAC>
+    pub secret: Option<String>,
+    // 是否为每条聊天消息发送webhook，加入/离开总是发送
+    pub messages: bool,
+}
+
+// 发送给webhook的事件，请求体为 {"event":"join","user":...,"room":...}
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum WebhookEvent {
+    Join {
+        user: String,
+        room: String,
+    },
+    Leave {
+        user: String,
+        room: String,
+    },
+    Message {
+        user: String,
+        room: String,
+        content: String,
+    },
+}
+
+// 每per时长内最多发送messages条
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+// 令牌桶，按两次读取之间经过的时间补充令牌
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: time::Instant,
+    // 连续被拒绝的次数，成功发送一次后清零
+    violations: u32,
+    muted_until: Option<time::Instant>,
+}
+
+#[derive(Debug)]
+pub struct ChatState {
+    peers: DashMap<PeerId, PeerHandle>,
+    next_id: AtomicU64,
+    config: ChatConfig,
+    // 房间名到房间状态的映射，广播只发给同一房间的peer；除默认房间外，房间为空时移除
+    rooms: DashMap<String, Arc<Room>>,
+    // 被/ban的IP，之后来自这些IP的连接在输入用户名之前就被拒绝
+    banned: DashMap<IpAddr, ()>,
+    // 按用户名排序的在线列表，随加入/离开增量更新，/who直接读取而不用遍历peers
+    roster: RwLock<Vec<String>>,
+    // 交给后台任务投递的webhook事件，投递失败不影响聊天
+    webhook: Option<Sender<WebhookEvent>>,
+    // 写聊天记录的后台任务，关闭时取出sender并等待任务把缓冲写完
+    transcript: Mutex<Option<(Sender<TranscriptEntry>, JoinHandle<()>)>>,
+    // 上次/export的时间
+    last_export: Mutex<Option<Instant>>,
+    // 开启share_head时获取分享链接的类型和大小
+    share_http: Option<reqwest::Client>,
+}
+
+// 一个房间的成员以及只在房间内共享的状态
+#[derive(Debug, Default)]
+struct Room {
+    members: DashSet<PeerId>,
+    // 最近分配的聊天消息序号，分配和广播在同一把锁内完成，保证各peer收到的顺序一致
+    seq: Mutex<u64>,
+    // 当前进行中的投票，新的/poll会覆盖旧的
+    poll: Mutex<Option<Poll>>,
+    // 最近的聊天消息，新用户加入房间时回放
+    history: Mutex<VecDeque<Arc<Message>>>,
+}
+
+// 聊天记录中的一行，写入时序列化为 {"at":<unix秒>,"room":...,"message":{...}}
+#[derive(Debug)]
+struct TranscriptEntry {
+    at: u64,
+    room: String,
+    message: Arc<Message>,
+}
+
+// peer的唯一标识，按连接顺序递增分配，与传输层地址无关
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(u64);
+
+// peers中保存的发送端，以及该peer订阅的消息类型
+#[derive(Debug)]
+struct PeerHandle {
+    tx: Sender<Arc<Message>>,
+    addr: SocketAddr,
+    username: String,
+    filter: MessageFilter,
+    echo: bool,
+    // 通知该连接的读循环退出
+    kicked: Arc<Notify>,
+    // 发送队列是否处于积压状态，每次积压只提醒一次
+    behind: AtomicBool,
+    // 当前所在房间
+    room: String,
+    // 暂停接收广播，期间的消息直接丢弃，只记录数量
+    paused: bool,
+    missed: AtomicUsize,
+    // 勿扰模式下暂存收到的私信以及要求送达回执的发送者，None表示未开启
+    dnd: Option<VecDeque<(Arc<Message>, Option<PeerId>)>>,
+}
+
+// 一条私信的投递结果，按会话计数
+#[derive(Debug, Default)]
+struct Delivery {
+    live: usize,
+    queued: usize,
+    // 勿扰队列已满而丢弃
+    dropped: usize,
+}
+
+// 被/kick或/ban断开的会话
+#[derive(Debug)]
+struct KickedPeer {
+    username: String,
+    addr: SocketAddr,
+    room: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum MessageFilter {
+    // 接收所有广播
+    #[default]
+    All,
+    // 只接收聊天消息，忽略加入/离开等通知
+    Text,
+}
+
+#[derive(Debug)]
+struct Poll {
+    question: String,
+    options: Vec<String>,
+    // 每个用户一票，重复投票时覆盖之前的选择
+    votes: HashMap<String, usize>,
+}
+
+/// 服务器发送给客户端的消息，JSON协议下每条消息序列化为一行：
+/// `{"type":"<variant>","data":...}`，type为变体名的snake_case形式。
+///
+/// - `join`/`left`/`system`/`typing`：data为字符串，例如 `{"type":"join","data":"alice"}`
+/// - `ping`：`{"type":"ping"}`，客户端应回复`/pong`或`{"cmd":"pong"}`
+/// - `rename`：`{"type":"rename","data":{"old":"alice","new":"bob"}}`
+/// - `direct`：`{"type":"direct","data":{"from":"alice","to":"bob","content":"hi"}}`
+/// - `action`：`{"type":"action","data":{"user":"alice","content":"waves"}}`
+/// - `code`：`{"type":"code","data":{"user":"alice","content":"fn main() {\n}"}}`
+/// - `text`：`{"type":"text","data":{"seq":1,"user":"alice","badge":null,"content":"hi"}}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Message {
+    Join(String),
+    Left(String),
+    System(String),
+    // 临时的输入状态提示，不会被记录
+    Typing(String),
+    // 心跳，客户端应回复/pong
+    Ping,
+    Rename {
+        old: String,
+        new: String,
+    },
+    // 私聊消息，只发送给目标用户
+    Direct {
+        from: String,
+        to: String,
+        content: String,
+    },
+    // /me 动作消息
+    Action {
+        user: String,
+        content: String,
+    },
+    // /code ... /endcode 之间原样保留格式的多行代码
+    Code {
+        user: String,
+        content: String,
+    },
+    Text {
+        // 聊天消息的序号，单调递增，客户端可据此发现丢失的消息
+        seq: u64,
+        user: String,
+        // 用户角色徽章，例如MOD
+        badge: Option<String>,
+        content: String,
+    },
+}
+
+// 客户端以/开头发送的命令
+#[derive(Debug)]
+enum Command {
+    Nick(String),
+    Me(String),
+    Who,
+    Quit,
+    Last,
+    Poll(String),
+    Vote(String),
+    Results,
+    Filter(String),
+    Share(String),
+    Echo(String),
+    LogoutOthers,
+    Typing,
+    Msg(String),
+    Join(String),
+    Leave,
+    Export,
+    Pong,
+    Code,
+    EndCode,
+    Pause,
+    Resume,
+    Auth(String),
+    Kick(String),
+    Ban(String),
+    Whois(String),
+    Dnd(String),
+    Resync(String),
+    Say(String),
+    RenameRoom(String),
+    Unknown(String),
+}
+
+// JSON协议下客户端可以用 {"cmd":"nick","args":"bob"} 代替 /nick bob
+#[derive(Debug, Deserialize)]
+struct CommandEnvelope {
+    cmd: String,
+    #[serde(default)]
+    args: String,
+}
+
+// stream是装箱后的读半边，不实现Debug
+pub struct Peer {
+    pub id: PeerId,
+    pub username: String,
+    room: String,
+    stream: BoxStream<'static, Result<String, LinesCodecError>>,
+    last: VecDeque<String>,
+    // 本次连接已广播的消息数
+    sent: usize,
+    kicked: Arc<Notify>,
+    // 上次广播/typing的时间，用于限制频率
+    last_typing: Option<Instant>,
+    // 是否已通过/auth成为管理员
+    admin: bool,
+    // 最近一次收到客户端数据的时间，写任务据此判断连接是否已失效
+    last_seen: Arc<Mutex<time::Instant>>,
+    // /code开始后收集的代码行，None表示不在代码块模式
+    code: Option<Vec<String>>,
+    // 开启限流时第一次发送才创建
+    bucket: Option<TokenBucket>,
+}
+// 处理一个客户端连接，传输层可以是TcpStream，也可以是测试用的内存管道
+pub async fn handle_connection<T>(stream: T, addr: SocketAddr, state: Arc<ChatState>) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // 将连接使用LinesCodec封装成Framed对象，按行进行数据分割，单行最长MSG_SIZE字节
+    let mut stream = Framed::new(stream, LinesCodec::new_with_max_length(MSG_SIZE));
+
+    if state.banned.contains_key(&addr.ip()) {
+        warn!("Rejected banned address {}", addr);
+        stream.send(state.prompt("You are banned")).await?;
+        return Ok(());
+    }
+
+    // 获取一个合法且未被占用的用户名，客户端中途断开时直接返回
+    let Some(username) = acquire_username(&mut stream, &state).await? else {
+        return Ok(());
+    };
+
+    // 配置了共享密码时，在加入广播之前校验；出错时同样要把名字移出roster
+    match check_password(&mut stream, &state).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Wrong password from {}", addr);
+            state.roster_remove(&username).await;
+            stream.send(state.prompt("Wrong password")).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            state.roster_remove(&username).await;
+            return Err(e);
+        }
+    }
+
+    // 同一用户名的并发连接数超过上限时拒绝
+    if state
+        .config
+        .max_sessions
+        .is_some_and(|max| state.session_count(&username) >= max)
+    {
+        warn!("Too many sessions for {}", username);
+        state.roster_remove(&username).await;
+        stream
+            .send(state.prompt("Too many connections for this name"))
+            .await?;
+        return Ok(());
+    }
+
+    // 将 用户的信息--sender stream 关联，开启异步task当broadcast时使用sender stream向每个用户client发送消息
+    let mut peer = state.add_peer(addr, username, stream);
+    let id = peer.id;
+    // 先回放最近的聊天记录，再广播加入
+    state.replay_history(&peer.room, &id).await;
+    let msg = Arc::new(Message::user_join(&peer.username));
+    // 向所在房间广播用户的到来
+    state.broadcast_to_room(&peer.room, msg, id).await?;
+
+    // 超长行报错后Framed会先返回一次None，之后丢弃到换行符为止继续读取
+    let mut skip_none = false;
+    // 最近一次用户活动的时间，心跳回应不算
+    let mut last_active = time::Instant::now();
+    loop {
+        let line = tokio::select! {
+            line = time::timeout_at(last_active + state.config.idle_timeout, peer.stream.next()) => match line {
+                Ok(line) => line,
+                Err(_) => {
+                    info!("Idle timeout for {}", addr);
+                    let msg = Arc::new(Message::system("disconnected due to inactivity"));
+                    state.send_to_peer(&id, msg).await;
+                    break;
+                }
+            },
+            // 被其他会话断开
+            _ = peer.kicked.notified() => break,
+        };
+        let Some(line) = line else {
+            if std::mem::take(&mut skip_none) {
+                continue;
+            }
+            break;
+        };
+        match line {
+            Ok(line) => {
+                *peer.last_seen.lock().await = time::Instant::now();
+                // 所在房间可能已被/rename-room改名，以peers中的记录为准
+                if let Some(handle) = state.peers.get(&id) {
+                    if handle.room != peer.room {
+                        peer.room = handle.room.clone();
+                    }
+                }
+                // 过滤控制字符，防止ANSI转义序列等污染其他用户的终端
+                let line = match state.sanitize(line) {
+                    Some(line) => line,
+                    None => {
+                        warn!("Rejected message with control chars from {}", addr);
+                        let msg = Arc::new(Message::system(
+                            "message rejected: control characters are not allowed",
+                        ));
+                        state.send_to_peer(&id, msg).await;
+                        continue;
+                    }
+                };
+                // 代码块模式下除心跳和/endcode外的行都原样收集
+                if peer.code.is_some() {
+                    match parse_command(&line) {
+                        Some(Command::Pong) => {}
+                        Some(Command::EndCode) => {
+                            last_active = time::Instant::now();
+                            finish_code(&state, &mut peer).await?;
+                        }
+                        _ => {
+                            last_active = time::Instant::now();
+                            if !peer.push_code(line) {
+                                let msg = Arc::new(Message::system(&format!(
+                                    "code block discarded: max {} lines, {} bytes",
+                                    MAX_CODE_LINES, MAX_CODE_BYTES
+                                )));
+                                state.send_to_peer(&id, msg).await;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                let cmd = parse_command(&line);
+                // 心跳回应不计入限流
+                if !matches!(cmd, Some(Command::Pong)) {
+                    if let Some(reply) = peer.throttle(state.config.rate_limit) {
+                        state
+                            .send_to_peer(&id, Arc::new(Message::system(&reply)))
+                            .await;
+                        continue;
+                    }
+                }
+                if let Some(cmd) = cmd {
+                    if !matches!(cmd, Command::Pong) {
+                        last_active = time::Instant::now();
+                    }
+                    if !handle_command(&state, &mut peer, cmd).await? {
+                        break;
+                    }
+                    continue;
+                }
+                if !peer.take_quota(state.config.session_quota) {
+                    let msg = Arc::new(Message::system(QUOTA_REACHED));
+                    state.send_to_peer(&id, msg).await;
+                    continue;
+                }
+                last_active = time::Instant::now();
+                peer.remember(&line, state.config.last_size);
+                let badge = state
+                    .config
+                    .roles
+                    .get(&peer.username)
+                    .map(|role| role.badge.clone());
+                state
+                    .broadcast_text(&peer.room, &peer.username, badge, line, id)
+                    .await?;
+            }
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                warn!("Line too long from {}", addr);
+                let msg = Arc::new(Message::system(&format!(
+                    "message too long, max {} bytes",
+                    MSG_SIZE
+                )));
+                state.send_to_peer(&id, msg).await;
+                skip_none = true;
+            }
+            Err(e) => {
+                warn!("Error reading line from stream: {}", e);
+                break;
+            }
+        }
+    }
+
+    // 用户退出chat
+    let msg = Arc::new(Message::user_left(&peer.username));
+    state.broadcast_to_room(&peer.room, msg, id).await?;
+    info!("user left:{}", peer.username);
+    state.remove_peer(&id);
+    state.roster_remove(&peer.username).await;
+
+    Ok(())
+}
+
+// 循环提示输入用户名，直到得到一个合法且未被占用的名字；名字在返回前已登记到roster
+async fn acquire_username<S>(stream: &mut S, state: &ChatState) -> Result<Option<String>>
+where
+    S: Stream<Item = Result<String, LinesCodecError>>
+        + Sink<String, Error = LinesCodecError>
+        + Unpin,
+{
+    // send方法是由futures这个crate的SinkExt  trait实现的，可以异步地将数据发送到流中
+    stream.send(state.prompt("Enter your name:")).await?;
+    // 角色密码输错的次数，超过上限后断开
+    let mut role_failures = 0;
+    loop {
+        // next方法返回Option<Result<>>，本来可以使用？？进行错误传播的，但是这个handler的返回类型为Result
+        let name = match stream.next().await {
+            Some(Ok(name)) => name.trim().to_string(),
+            Some(Err(e)) => {
+                warn!("Username codec error:{}", e.to_string());
+                return Ok(None);
+            }
+            None => {
+                warn!("No username provided");
+                return Ok(None);
+            }
+        };
+        let prompt = if name.is_empty() {
+            "Name can't be empty, choose another:".to_string()
+        } else if name.chars().count() > MAX_NAME_LEN {
+            format!(
+                "Name longer than {} characters, choose another:",
+                MAX_NAME_LEN
+            )
+        } else if has_control_chars(&name) {
+            "Name can't contain control characters, choose another:".to_string()
+        } else if state.config.is_blocked_name(&name) {
+            "Name is reserved or not allowed, choose another:".to_string()
+        } else {
+            // 有角色的用户名要先输入角色密码，徽章不能靠自称获得
+            if let Some(role) = state.config.roles.get(&name) {
+                stream
+                    .send(state.prompt(&format!("Enter password for {}:", name)))
+                    .await?;
+                match stream.next().await {
+                    Some(Ok(input)) if input.trim() == role.password => {}
+                    Some(Ok(_)) => {
+                        warn!("Wrong role password for {}", name);
+                        role_failures += 1;
+                        if role_failures >= MAX_PASSWORD_ATTEMPTS {
+                            stream.send(state.prompt("Wrong password")).await?;
+                            return Ok(None);
+                        }
+                        stream
+                            .send(state.prompt("Wrong password, choose another name:"))
+                            .await?;
+                        continue;
+                    }
+                    _ => return Ok(None),
+                }
+            }
+            if state.roster_add(&name).await {
+                return Ok(Some(name));
+            }
+            "Name taken, choose another:".to_string()
+        };
+        stream.send(state.prompt(&prompt)).await?;
+    }
+}
+
+// 未配置密码时直接通过；否则最多允许输入MAX_PASSWORD_ATTEMPTS次
+async fn check_password<S>(stream: &mut S, state: &ChatState) -> Result<bool>
+where
+    S: Stream<Item = Result<String, LinesCodecError>>
+        + Sink<String, Error = LinesCodecError>
+        + Unpin,
+{
+    let Some(password) = &state.config.join_password else {
+        return Ok(true);
+    };
+    stream.send(state.prompt("Enter password:")).await?;
+    for attempt in 1..=MAX_PASSWORD_ATTEMPTS {
+        match stream.next().await {
+            Some(Ok(input)) if input.trim() == password => return Ok(true),
+            Some(Ok(_)) if attempt < MAX_PASSWORD_ATTEMPTS => {
+                stream
+                    .send(state.prompt("Wrong password, try again:"))
+                    .await?;
+            }
+            Some(Ok(_)) => {}
+            _ => return Ok(false),
+        }
+    }
+    Ok(false)
+}
+
+// 结束代码块模式，把收集的代码作为一条消息广播到所在房间
+async fn finish_code(state: &ChatState, peer: &mut Peer) -> Result<()> {
+    let lines = peer.code.take().unwrap_or_default();
+    let reply = if lines.is_empty() {
+        "empty code block"
+    } else if !peer.take_quota(state.config.session_quota) {
+        QUOTA_REACHED
+    } else {
+        let msg = Arc::new(Message::Code {
+            user: peer.username.clone(),
+            content: lines.join("\n"),
+        });
+        state
+            .broadcast_to_room(&peer.room, msg.clone(), peer.id)
+            .await?;
+        state.send_to_peer(&peer.id, msg).await;
+        return Ok(());
+    };
+    state
+        .send_to_peer(&peer.id, Arc::new(Message::system(reply)))
+        .await;
+    Ok(())
+}
+
+// 按天滚动写聊天记录，定期flush；channel关闭后flush并退出
+async fn write_transcript(dir: PathBuf, keep: Option<usize>, mut rx: Receiver<TranscriptEntry>) {
+    if let Err(e) = fs::create_dir_all(&dir).await {
+        warn!("Create transcript dir error:{}", e);
+        return;
+    }
+    // 当前打开的文件及其日期
+    let mut current: Option<(String, BufWriter<File>)> = None;
+    let mut flush = time::interval(TRANSCRIPT_FLUSH);
+    loop {
+        tokio::select! {
+            entry = rx.recv() => {
+                let Some(entry) = entry else {
+                    break;
+                };
+                let day = utc_date(entry.at);
+                if current.as_ref().is_none_or(|(d, _)| *d != day) {
+                    if let Some((_, mut file)) = current.take() {
+                        if let Err(e) = file.flush().await {
+                            warn!("Flush transcript error:{}", e);
+                        }
+                    }
+                    // 启动时也会执行，压缩上次运行留下的旧记录
+                    let (rotated, today) = (dir.clone(), day.clone());
+                    let compress = move || compress_transcripts(&rotated, &today, keep);
+                    match tokio::task::spawn_blocking(compress).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => warn!("Compress transcripts error:{}", e),
+                        Err(e) => warn!("Compress transcripts task error:{}", e),
+                    }
+                    let path = dir.join(format!("chat-{}.log", day));
+                    match OpenOptions::new().create(true).append(true).open(&path).await {
+                        Ok(file) => current = Some((day, BufWriter::new(file))),
+                        Err(e) => {
+                            warn!("Open transcript {} error:{}", path.display(), e);
+                            continue;
+                        }
+                    }
+                }
+                let line = serde_json::json!({
+                    "at": entry.at,
+                    "room": entry.room,
+                    "message": &*entry.message,
+                });
+                if let Some((_, file)) = current.as_mut() {
+                    if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()).await {
+                        warn!("Write transcript error:{}", e);
+                    }
+                }
+            }
+            _ = flush.tick() => {
+                if let Some((_, file)) = current.as_mut() {
+                    if let Err(e) = file.flush().await {
+                        warn!("Flush transcript error:{}", e);
+                    }
+                }
+            }
+        }
+    }
+    if let Some((_, mut file)) = current {
+        if let Err(e) = file.flush().await {
+            warn!("Flush transcript error:{}", e);
+        }
+    }
+}
+
+// 把today之前的chat-*.log压缩为chat-*.log.gz，keep不为None时只保留最近的keep个压缩文件
+fn compress_transcripts(dir: &Path, today: &str, keep: Option<usize>) -> std::io::Result<()> {
+    let mut compressed = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !name.starts_with("chat-") {
+            continue;
+        }
+        if name.ends_with(".log.gz") {
+            compressed.push(path);
+        } else if name.ends_with(".log") && name != format!("chat-{}.log", today) {
+            let gz = dir.join(format!("{}.gz", name));
+            let mut encoder = GzEncoder::new(std::fs::File::create(&gz)?, Compression::default());
+            std::io::copy(&mut std::fs::File::open(&path)?, &mut encoder)?;
+            encoder.finish()?;
+            std::fs::remove_file(&path)?;
+            compressed.push(gz);
+        }
+    }
+    if let Some(keep) = keep {
+        // 文件名中的日期按字典序即时间顺序
+        compressed.sort();
+        compressed.dedup();
+        let excess = compressed.len().saturating_sub(keep);
+        for path in &compressed[..excess] {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 按B、KB、MB、GB显示文件大小
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+// unix秒对应的UTC日期，格式为YYYY-MM-DD
+fn utc_date(secs: u64) -> String {
+    // 按公历的400年周期从天数换算年月日
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// 逐个投递webhook事件，失败时按递增的间隔重试，超过次数后放弃
+async fn deliver_webhooks(config: WebhookConfig, mut rx: Receiver<WebhookEvent>) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Create webhook client error:{}", e);
+            return;
+        }
+    };
+    while let Some(event) = rx.recv().await {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Encode webhook event error:{}", e);
+                continue;
+            }
+        };
+        for attempt in 1..=WEBHOOK_RETRIES {
+            let mut req = client
+                .post(&config.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &config.secret {
+                req = req.header(WEBHOOK_SIGNATURE_HEADER, sign_webhook(secret, &body));
+            }
+            match req.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => break,
+                Err(e) => {
+                    warn!("Webhook attempt {} failed:{}", attempt, e);
+                    if attempt < WEBHOOK_RETRIES {
+                        time::sleep(WEBHOOK_BACKOFF * attempt).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn sign_webhook(secret: &str, body: &[u8]) -> String {
+    // HMAC接受任意长度的密钥，这里不会失败
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// 等待下一次心跳，返回心跳周期；未开启心跳时永远不返回
+async fn tick(heartbeat: &mut Option<time::Interval>) -> Duration {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+            interval.period()
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// 用户名和房间名会出现在其他用户的终端上，不允许包含任何控制字符
+fn has_control_chars(name: &str) -> bool {
+    name.chars().any(char::is_control)
+}
+
+// 解析/msg的参数，返回是否要求回执、接收者和内容
+fn msg_args(args: &str) -> Option<(bool, &str, &str)> {
+    let (receipt, args) = match args.split_once(char::is_whitespace) {
+        Some(("-r", rest)) => (true, rest.trim_start()),
+        _ => (false, args),
+    };
+    let (to, content) = args.split_once(char::is_whitespace)?;
+    let content = content.trim();
+    (!content.is_empty()).then_some((receipt, to, content))
+}
+
+// 将以/开头的行解析为命令，普通消息返回None
+fn parse_command(line: &str) -> Option<Command> {
+    if let Ok(envelope) = serde_json::from_str::<CommandEnvelope>(line) {
+        // JSON转义的控制字符在解码后才出现，读循环的过滤拦不住，这里再剔除一次
+        let args = envelope
+            .args
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\t')
+            .collect::<String>();
+        return Some(command(&envelope.cmd, args.trim().to_string()));
+    }
+    let line = line.trim_start().strip_prefix('/')?;
+    let (cmd, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    Some(command(cmd, args.trim().to_string()))
+}
+
+fn command(cmd: &str, args: String) -> Command {
+    match cmd {
+        "nick" => Command::Nick(args),
+        "me" => Command::Me(args),
+        "who" => Command::Who,
+        "quit" => Command::Quit,
+        "last" => Command::Last,
+        "poll" => Command::Poll(args),
+        "vote" => Command::Vote(args),
+        "results" => Command::Results,
+        "filter" => Command::Filter(args),
+        "share" => Command::Share(args),
+        "echo" => Command::Echo(args),
+        "logout-others" => Command::LogoutOthers,
+        "typing" => Command::Typing,
+        "msg" => Command::Msg(args),
+        "join" => Command::Join(args),
+        "leave" => Command::Leave,
+        "export" => Command::Export,
+        "pong" => Command::Pong,
+        "code" => Command::Code,
+        "endcode" => Command::EndCode,
+        "pause" => Command::Pause,
+        "resume" => Command::Resume,
+        "auth" => Command::Auth(args),
+        "kick" => Command::Kick(args),
+        "ban" => Command::Ban(args),
+        "whois" => Command::Whois(args),
+        "dnd" => Command::Dnd(args),
+        "resync" => Command::Resync(args),
+        "say" => Command::Say(args),
+        "rename-room" => Command::RenameRoom(args),
+        _ => Command::Unknown(cmd.to_string()),
+    }
+}
+
+// 执行命令，结果只回复给发送者；返回false表示客户端要求断开连接
+async fn handle_command(state: &ChatState, peer: &mut Peer, cmd: Command) -> Result<bool> {
+    let reply = match cmd {
+        Command::Nick(name) => {
+            if name.is_empty() {
+                "usage: /nick <newname>".to_string()
+            } else if state
+                .config
+                .max_sessions
+                .is_some_and(|max| state.session_count(&name) >= max)
+            {
+                format!("too many connections for {}", name)
+            } else if name.chars().count() > MAX_NAME_LEN {
+                format!("name longer than {} characters", MAX_NAME_LEN)
+            } else if has_control_chars(&name) {
+                "name can't contain control characters".to_string()
+            } else if state.config.is_blocked_name(&name) {
+                format!("name not allowed: {}", name)
+            } else if state.config.roles.contains_key(&name) {
+                // /nick没有输入密码的步骤，角色用户名只能在登录时使用
+                format!("name reserved, reconnect to log in as {}", name)
+            } else if !state.roster_add(&name).await {
+                format!("name taken: {}", name)
+            } else {
+                let old = std::mem::replace(&mut peer.username, name.clone());
+                if let Some(mut handle) = state.peers.get_mut(&peer.id) {
+                    handle.username = name.clone();
+                }
+                state.roster_remove(&old).await;
+                let msg = Arc::new(Message::Rename { old, new: name });
+                state
+                    .broadcast_to_room(&peer.room, msg.clone(), peer.id)
+                    .await?;
+                state.send_to_peer(&peer.id, msg).await;
+                return Ok(true);
+            }
+        }
+        Command::Me(action) => {
+            if action.is_empty() {
+                "usage: /me <action>".to_string()
+            } else if !peer.take_quota(state.config.session_quota) {
+                QUOTA_REACHED.to_string()
+            } else {
+                let msg = Arc::new(Message::Action {
+                    user: peer.username.clone(),
+                    content: action,
+                });
+                state
+                    .broadcast_to_room(&peer.room, msg.clone(), peer.id)
+                    .await?;
+                state.send_to_peer(&peer.id, msg).await;
+                return Ok(true);
+            }
+        }
+        Command::Quit => {
+            state
+                .send_to_peer(&peer.id, Arc::new(Message::system("bye")))
+                .await;
+            return Ok(false);
+        }
+        Command::Last => match peer.last.back() {
+            Some(last) => format!("last: {}", last),
+            None => "no previous message".to_string(),
+        },
+        Command::Poll(args) => match Poll::parse(&args) {
+            Some(_) if !peer.take_quota(state.config.session_quota) => QUOTA_REACHED.to_string(),
+            Some(poll) => {
+                let msg = Arc::new(Message::system(&poll.to_string()));
+                if let Some(room) = state.room(&peer.room) {
+                    *room.poll.lock().await = Some(poll);
+                }
+                state
+                    .broadcast_to_room(&peer.room, msg.clone(), peer.id)
+                    .await?;
+                state.send_to_peer(&peer.id, msg).await;
+                return Ok(true);
+            }
+            None => format!(
+                "usage: /poll \"question\" opt1 opt2 ... (2-{} options)",
+                MAX_POLL_OPTIONS
+            ),
+        },
+        Command::Vote(choice) => match state.room(&peer.room) {
+            Some(room) => match room.poll.lock().await.as_mut() {
+                Some(poll) => poll.vote(&peer.username, &choice),
+                None => "no active poll".to_string(),
+            },
+            None => "no active poll".to_string(),
+        },
+        Command::Results => match state.room(&peer.room) {
+            Some(room) => match room.poll.lock().await.as_ref() {
+                Some(poll) => poll.results(),
+                None => "no active poll".to_string(),
+            },
+            None => "no active poll".to_string(),
+        },
+        Command::Filter(mode) => match mode.as_str() {
+            "all" | "text" => {
+                let filter = match mode.as_str() {
+                    "text" => MessageFilter::Text,
+                    _ => MessageFilter::All,
+                };
+                if let Some(mut handle) = state.peers.get_mut(&peer.id) {
+                    handle.filter = filter;
+                }
+                format!("filter set to {}", mode)
+            }
+            _ => "usage: /filter <all|text>".to_string(),
+        },
+        Command::Share(args) => {
+            let (link, desc) = args.split_once(' ').unwrap_or((&args, ""));
+            let url = Url::parse(link)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+            match url {
+                Some(_) if !peer.take_quota(state.config.session_quota) => {
+                    QUOTA_REACHED.to_string()
+                }
+                Some(url) => {
+                    let mut content = format!("{} shared {}", peer.username, url);
+                    if let Some(meta) = state.link_metadata(&url).await {
+                        content = format!("{} ({})", content, meta);
+                    }
+                    if !desc.trim().is_empty() {
+                        content = format!("{} - {}", content, desc.trim());
+                    }
+                    let msg = Arc::new(Message::system(&content));
+                    state
+                        .broadcast_to_room(&peer.room, msg.clone(), peer.id)
+                        .await?;
+                    state.send_to_peer(&peer.id, msg).await;
+                    return Ok(true);
+                }
+                None => "usage: /share <http(s) url> [description]".to_string(),
+            }
+        }
+        Command::Echo(mode) => match mode.as_str() {
+            "on" | "off" => {
+                if let Some(mut handle) = state.peers.get_mut(&peer.id) {
+                    handle.echo = mode == "on";
+                }
+                format!("echo {}", mode)
+            }
+            _ => "usage: /echo <on|off>".to_string(),
+        },
+        Command::LogoutOthers => {
+            let count = state.logout_others(&peer.username, peer.id);
+            format!("disconnected {} other session(s)", count)
+        }
+        Command::Typing => {
+            // 每个用户每秒最多广播一次，超出的直接忽略
+            let now = Instant::now();
+            if peer
+                .last_typing
+                .is_none_or(|last| now.duration_since(last) >= TYPING_INTERVAL)
+            {
+                peer.last_typing = Some(now);
+                let msg = Arc::new(Message::Typing(peer.username.clone()));
+                state.broadcast_to_room(&peer.room, msg, peer.id).await?;
+            }
+            return Ok(true);
+        }
+        Command::Who => {
+            let roster = state.roster.read().await;
+            let names: Vec<String> = roster
+                .iter()
+                .map(|name| match state.config.roles.get(name) {
+                    Some(role) => format!("[{}]{}", role.badge, name),
+                    None => name.clone(),
+                })
+                .collect();
+            format!("online({}): {}", names.len(), names.join(", "))
+        }
+        // /msg -r <username> <text> 要求送达回执，私信进入对方的发送队列时通知发送者
+        Command::Msg(args) => match msg_args(&args) {
+            Some((receipt, to, content)) => {
+                let msg = Arc::new(Message::Direct {
+                    from: peer.username.clone(),
+                    to: to.to_string(),
+                    content: content.to_string(),
+                });
+                let receipt = receipt.then_some(peer.id);
+                match state.send_to(to, msg.clone(), receipt).await {
+                    Delivery {
+                        live: 0,
+                        queued: 0,
+                        dropped: 0,
+                    } => format!("no such user: {}", to),
+                    Delivery {
+                        live: 0, queued: 0, ..
+                    } => {
+                        format!("{}'s queue is full, message dropped", to)
+                    }
+                    Delivery { live: 0, .. } => {
+                        format!("{} is in do-not-disturb, message queued", to)
+                    }
+                    _ => {
+                        // 发给自己时已经送达，不再重复回显
+                        if to != peer.username {
+                            state.send_to_peer(&peer.id, msg).await;
+                        }
+                        if receipt.is_none() {
+                            return Ok(true);
+                        }
+                        format!("[delivered to {}]", to)
+                    }
+                }
+            }
+            None => "usage: /msg [-r] <username> <text>".to_string(),
+        },
+        Command::Join(room) => {
+            if room.is_empty() || room.contains(char::is_whitespace) {
+                "usage: /join <room>".to_string()
+            } else if room.chars().count() > MAX_NAME_LEN {
+                format!("room name longer than {} characters", MAX_NAME_LEN)
+            } else if has_control_chars(&room) {
+                "room name can't contain control characters".to_string()
+            } else if room == peer.room {
+                format!("already in {}", room)
+            } else {
+                state.switch_room(peer, room).await?;
+                format!("joined {}", peer.room)
+            }
+        }
+        Command::Leave => {
+            if peer.room == DEFAULT_ROOM {
+                format!("already in {}", DEFAULT_ROOM)
+            } else {
+                state.switch_room(peer, DEFAULT_ROOM.to_string()).await?;
+                format!("back in {}", DEFAULT_ROOM)
+            }
+        }
+        // 读循环已经更新了last_seen，这里不需要回复
+        Command::Pong => return Ok(true),
+        Command::Code => {
+            peer.code = Some(Vec::new());
+            "code mode: send /endcode to finish".to_string()
+        }
+        Command::EndCode => "no code block started".to_string(),
+        Command::Pause => {
+            if let Some(mut handle) = state.peers.get_mut(&peer.id) {
+                handle.paused = true;
+            }
+            "paused, send /resume to receive messages again".to_string()
+        }
+        Command::Resume => match state.peers.get_mut(&peer.id) {
+            Some(mut handle) if handle.paused => {
+                handle.paused = false;
+                let missed = handle.missed.swap(0, Ordering::Relaxed);
+                format!("resumed, you missed {} message(s)", missed)
+            }
+            _ => "not paused".to_string(),
+        },
+        // /dnd开启勿扰，/dnd off关闭并投递期间暂存的私信
+        Command::Dnd(args) => match args.as_str() {
+            "" => match state.peers.get_mut(&peer.id) {
+                Some(mut handle) => match &handle.dnd {
+                    Some(queue) => format!("already in do-not-disturb, {} queued", queue.len()),
+                    None => {
+                        handle.dnd = Some(VecDeque::new());
+                        "do-not-disturb on, direct messages will be queued".to_string()
+                    }
+                },
+                None => "not connected".to_string(),
+            },
+            "off" => {
+                let queued = state
+                    .peers
+                    .get_mut(&peer.id)
+                    .and_then(|mut handle| handle.dnd.take());
+                match queued {
+                    Some(queued) => {
+                        let count = queued.len();
+                        for (msg, receipt) in queued {
+                            state.send_to_peer(&peer.id, msg).await;
+                            if let Some(sender) = receipt {
+                                let notice = format!("[delivered to {}]", peer.username);
+                                state.try_send_to_peer(&sender, Arc::new(Message::system(&notice)));
+                            }
+                        }
+                        format!("do-not-disturb off, {} queued message(s) delivered", count)
+                    }
+                    None => "not in do-not-disturb".to_string(),
+                }
+            }
+            _ => "usage: /dnd [off]".to_string(),
+        },
+        // 客户端发现序号不连续时，补发当前房间中序号更大的历史消息
+        Command::Resync(args) => match args.parse::<u64>() {
+            Ok(after) => match state.resync(&peer.room, &peer.id, after).await {
+                (count, Some(oldest)) => format!(
+                    "messages up to #{} are no longer available, resynced {} message(s)",
+                    oldest - 1,
+                    count
+                ),
+                (count, None) => format!("resynced {} message(s)", count),
+            },
+            Err(_) => "usage: /resync <seq>".to_string(),
+        },
+        Command::Auth(token) => match &state.config.admin_token {
+            Some(admin_token) if !token.is_empty() && token == *admin_token => {
+                peer.admin = true;
+                info!("{} authenticated as admin", peer.username);
+                "authenticated as admin".to_string()
+            }
+            _ => "invalid token".to_string(),
+        },
+        Command::Kick(_)
+        | Command::Ban(_)
+        | Command::Whois(_)
+        | Command::Export
+        | Command::Say(_)
+        | Command::RenameRoom(_)
+            if !peer.admin =>
+        {
+            "permission denied".to_string()
+        }
+        // 管理员向指定房间广播系统消息，不要求自己在该房间
+        Command::Say(args) => match args.split_once(char::is_whitespace) {
+            Some((room, text)) if !text.trim().is_empty() => {
+                if state.room(room).is_none() {
+                    format!("no such room: {}", room)
+                } else {
+                    let msg = Arc::new(Message::system(text.trim()));
+                    state.broadcast_to_room(room, msg, peer.id).await?;
+                    format!("sent to {}", room)
+                }
+            }
+            _ => "usage: /say <room> <text>".to_string(),
+        },
+        Command::RenameRoom(args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [old, _] | [_, old] if old == DEFAULT_ROOM => {
+                format!("can't rename {}", DEFAULT_ROOM)
+            }
+            [_, new] if new.chars().count() > MAX_NAME_LEN => {
+                format!("room name longer than {} characters", MAX_NAME_LEN)
+            }
+            [_, new] if has_control_chars(new) => {
+                "room name can't contain control characters".to_string()
+            }
+            [old, _] if state.room(old).is_none() => format!("no such room: {}", old),
+            [old, new] => {
+                if state.rename_room(old, new) {
+                    if peer.room == old {
+                        peer.room = new.to_string();
+                    }
+                    let msg =
+                        Arc::new(Message::system(&format!("room {} renamed to {}", old, new)));
+                    state.broadcast_to_room(new, msg, peer.id).await?;
+                    format!("renamed {} to {}", old, new)
+                } else {
+                    format!("room {} already exists", new)
+                }
+            }
+            _ => "usage: /rename-room <old> <new>".to_string(),
+        },
+        // 每次导出都会写一个文件，全局限制频率
+        Command::Export => match state.allow_export().await {
+            Err(wait) => format!("export rate limited, try again in {}s", wait.as_secs() + 1),
+            Ok(()) => match state.export_history().await {
+                Ok(path) => format!("history exported to {}", path.display()),
+                Err(e) => {
+                    warn!("Export history error:{}", e);
+                    "export failed".to_string()
+                }
+            },
+        },
+        Command::Kick(name) => {
+            if name.is_empty() {
+                "usage: /kick <username>".to_string()
+            } else {
+                let kicked = state.kick_peers(|p| p.username == name, "you were kicked");
+                if kicked.is_empty() {
+                    format!("no such user: {}", name)
+                } else {
+                    state.announce_removed(&kicked, "kicked", peer.id).await?;
+                    format!("kicked {}", name)
+                }
+            }
+        }
+        // 参数可以是IP，也可以是用户名；按用户名封禁时封禁该用户所有会话的IP
+        Command::Ban(target) => match target.parse::<IpAddr>() {
+            _ if target.is_empty() => "usage: /ban <ip|username>".to_string(),
+            Ok(ip) => {
+                state.banned.insert(ip, ());
+                let kicked = state.kick_peers(|p| p.addr.ip() == ip, "you were banned");
+                state.announce_removed(&kicked, "banned", peer.id).await?;
+                format!("banned {}", ip)
+            }
+            Err(_) => {
+                let kicked = state.kick_peers(|p| p.username == target, "you were banned");
+                if kicked.is_empty() {
+                    format!("no such user: {}", target)
+                } else {
+                    for kicked in &kicked {
+                        state.banned.insert(kicked.addr.ip(), ());
+                    }
+                    state.announce_removed(&kicked, "banned", peer.id).await?;
+                    format!("banned {}", target)
+                }
+            }
+        },
+        // 列出该用户每个会话的地址、房间和发送队列中尚未写出的消息数
+        Command::Whois(name) => {
+            let sessions: Vec<String> = state
+                .peers
+                .iter()
+                .filter(|p| p.username == name)
+                .map(|p| {
+                    format!(
+                        "{} {} room={} queued={}/{}",
+                        p.username,
+                        p.addr,
+                        p.room,
+                        p.queue_depth(),
+                        p.tx.max_capacity()
+                    )
+                })
+                .collect();
+            if name.is_empty() {
+                "usage: /whois <username>".to_string()
+            } else if sessions.is_empty() {
+                format!("no such user: {}", name)
+            } else {
+                sessions.join("; ")
+            }
+        }
+        Command::Unknown(cmd) => format!("unknown command: /{}", cmd),
+    };
+    state
+        .send_to_peer(&peer.id, Arc::new(Message::system(&reply)))
+        .await;
+    Ok(true)
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            protocol: Protocol::Json,
+            sanitize: SanitizeMode::Strip,
+            last_size: 10,
+            session_quota: None,
+            echo: true,
+            roles: HashMap::new(),
+            show_seq: false,
+            unique_names: true,
+            max_sessions: None,
+            idle_timeout: IDLE_TIMEOUT,
+            heartbeat: None,
+            join_password: None,
+            admin_token: None,
+            export_dir: PathBuf::from("."),
+            rate_limit: None,
+            webhook: None,
+            transcript_dir: None,
+            transcript_joins: false,
+            transcript_keep: None,
+            blocked_names: RESERVED_NAMES.iter().map(|s| s.to_string()).collect(),
+            share_head: false,
+        }
+    }
+}
+
+impl ChatConfig {
+    // 从环境变量读取配置，未设置时使用默认值
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if env::var("CHAT_PROTOCOL").is_ok_and(|p| p == "text") {
+            config.protocol = Protocol::Text;
+        }
+        if let Ok(mode) = env::var("CHAT_SANITIZE") {
+            config.sanitize = match mode.as_str() {
+                "reject" => SanitizeMode::Reject,
+                _ => SanitizeMode::Strip,
+            };
+        }
+        if let Some(size) = env::var("CHAT_LAST_SIZE").ok().and_then(|s| s.parse().ok()) {
+            config.last_size = size;
+        }
+        if let Some(quota) = env::var("CHAT_SESSION_QUOTA")
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            config.session_quota = Some(quota);
+        }
+        config.echo = env::var("CHAT_ECHO").map_or(true, |v| v != "0" && v != "false");
+        config.unique_names =
+            env::var("CHAT_UNIQUE_NAMES").map_or(true, |v| v != "0" && v != "false");
+        if let Some(max) = env::var("CHAT_MAX_SESSIONS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            config.max_sessions = Some(max);
+        }
+        if let Some(secs) = env::var("CHAT_IDLE_SECS").ok().and_then(|s| s.parse().ok()) {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        config.heartbeat = env::var("CHAT_HEARTBEAT_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        config.join_password = env::var("CHAT_PASSWORD").ok().filter(|p| !p.is_empty());
+        config.admin_token = env::var("CHAT_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        if let Ok(dir) = env::var("CHAT_EXPORT_DIR") {
+            config.export_dir = PathBuf::from(dir);
+        }
+        config.webhook = env::var("CHAT_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .map(|url| WebhookConfig {
+                url,
+                secret: env::var("CHAT_WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                messages: env::var("CHAT_WEBHOOK_MESSAGES").is_ok_and(|v| v == "1" || v == "true"),
+            });
+        config.transcript_dir = env::var("CHAT_TRANSCRIPT_DIR").ok().map(PathBuf::from);
+        config.transcript_joins =
+            env::var("CHAT_TRANSCRIPT_JOINS").is_ok_and(|v| v == "1" || v == "true");
+        config.transcript_keep = env::var("CHAT_TRANSCRIPT_KEEP")
+            .ok()
+            .and_then(|v| v.parse().ok());
+        // 格式为 条数/秒数，例如5/10
+        config.rate_limit = env::var("CHAT_RATE_LIMIT").ok().and_then(|limit| {
+            let (messages, secs) = limit.split_once('/')?;
+            Some(RateLimit {
+                messages: messages.trim().parse().ok().filter(|n| *n > 0)?,
+                per: Duration::from_secs(secs.trim().parse().ok().filter(|n| *n > 0)?),
+            })
+        });
+        config.show_seq = env::var("CHAT_SHOW_SEQ").is_ok_and(|v| v == "1" || v == "true");
+        config.share_head = env::var("CHAT_SHARE_HEAD").is_ok_and(|v| v == "1" || v == "true");
+        // 格式为 alice:MOD:password,bob:ADMIN:password，没有密码的项被忽略
+        if let Ok(roles) = env::var("CHAT_ROLES") {
+            config.roles = roles
+                .split(',')
+                .filter_map(|entry| {
+                    let mut parts = entry.trim().splitn(3, ':');
+                    let name = parts.next().filter(|n| !n.is_empty())?;
+                    let badge = parts.next().filter(|b| !b.is_empty())?;
+                    let Some(password) = parts.next().filter(|p| !p.is_empty()) else {
+                        warn!("Ignoring role {} for {} without a password", badge, name);
+                        return None;
+                    };
+                    let role = Role {
+                        badge: badge.to_string(),
+                        password: password.to_string(),
+                    };
+                    Some((name.to_string(), role))
+                })
+                .collect();
+        }
+        // 在保留名之外追加的禁用名，逗号分隔
+        if let Ok(names) = env::var("CHAT_BLOCKED_NAMES") {
+            config.blocked_names.extend(
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty()),
+            );
+        }
+        config
+    }
+
+    // 配置了角色的用户名本身可以使用，登录时另外校验角色密码；只是大小写不同的视为冒充
+    fn is_blocked_name(&self, name: &str) -> bool {
+        if self.roles.contains_key(name) {
+            return false;
+        }
+        self.blocked_names.contains(&name.to_lowercase())
+            || self.roles.iter().any(|(holder, role)| {
+                role.badge.eq_ignore_ascii_case(name) || holder.eq_ignore_ascii_case(name)
+            })
+    }
+}
+
+impl ChatState {
+    // 配置了webhook时会启动投递任务，需要在tokio运行时中调用
+    pub fn new(config: ChatConfig) -> Self {
+        let webhook = config.webhook.clone().map(|webhook| {
+            let (tx, rx) = channel(WEBHOOK_QUEUE);
+            tokio::spawn(deliver_webhooks(webhook, rx));
+            tx
+        });
+        let transcript = config.transcript_dir.clone().map(|dir| {
+            let (tx, rx) = channel(TRANSCRIPT_QUEUE);
+            let keep = config.transcript_keep;
+            (tx, tokio::spawn(write_transcript(dir, keep, rx)))
+        });
+        let share_http = match config.share_head {
+            true => reqwest::Client::builder()
+                .timeout(SHARE_HEAD_TIMEOUT)
+                .redirect(reqwest::redirect::Policy::limited(SHARE_MAX_REDIRECTS))
+                .build()
+                .map_err(|e| warn!("Create share client error:{}", e))
+                .ok(),
+            false => None,
+        };
+        Self {
+            peers: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            config,
+            rooms: DashMap::new(),
+            banned: DashMap::new(),
+            roster: RwLock::new(Vec::new()),
+            webhook,
+            transcript: Mutex::new(transcript),
+            last_export: Mutex::new(None),
+            share_http,
+        }
+    }
+    // HEAD请求分享的链接，返回 "类型, 大小"；未开启、请求失败或没有对应响应头时返回None
+    async fn link_metadata(&self, url: &Url) -> Option<String> {
+        let client = self.share_http.as_ref()?;
+        let res = match client
+            .head(url.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+        {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("HEAD shared link {} error:{}", url, e);
+                return None;
+            }
+        };
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+        };
+        // 去掉charset等参数，只保留类型
+        let kind = header(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let size = header(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.parse().ok())
+            .map(format_size);
+        let meta: Vec<String> = kind.into_iter().chain(size).collect();
+        (!meta.is_empty()).then(|| meta.join(", "))
+    }
+    // 按配置的协议编码发给客户端的一行
+    fn encode(&self, msg: &Message) -> String {
+        match self.config.protocol {
+            Protocol::Json => serde_json::to_string(msg).unwrap_or_else(|e| {
+                warn!("Encode message error:{}", e);
+                String::new()
+            }),
+            Protocol::Text => match (self.config.show_seq, msg.seq()) {
+                (true, Some(seq)) => format!("#{} {}", seq, msg),
+                _ => format!("{}", msg),
+            },
+        }
+    }
+    // 登录阶段的提示，纯文本协议下原样发送，JSON协议下作为system消息发送
+    fn prompt(&self, text: &str) -> String {
+        match self.config.protocol {
+            Protocol::Json => self.encode(&Message::system(text)),
+            Protocol::Text => text.to_string(),
+        }
+    }
+    // 除tab外的控制字符都视为非法；Strip模式下剔除，Reject模式下返回None
+    fn sanitize(&self, line: String) -> Option<String> {
+        let is_illegal = |c: char| c.is_control() && c != '\t';
+        if !line.chars().any(is_illegal) {
+            return Some(line);
+        }
+        match self.config.sanitize {
+            SanitizeMode::Strip => Some(line.chars().filter(|c| !is_illegal(*c)).collect()),
+            SanitizeMode::Reject => None,
+        }
+    }
+    // 登记用户名，要求唯一且已被占用时返回false；检查和插入在同一把写锁内完成
+    async fn roster_add(&self, username: &str) -> bool {
+        let mut roster = self.roster.write().await;
+        match roster.binary_search_by(|name| name.as_str().cmp(username)) {
+            Ok(_) if self.config.unique_names => false,
+            Ok(pos) | Err(pos) => {
+                roster.insert(pos, username.to_string());
+                true
+            }
+        }
+    }
+    async fn roster_remove(&self, username: &str) {
+        let mut roster = self.roster.write().await;
+        if let Ok(pos) = roster.binary_search_by(|name| name.as_str().cmp(username)) {
+            roster.remove(pos);
+        }
+    }
+    // 断开满足条件的所有会话
+    fn kick_peers(&self, pred: impl Fn(&PeerHandle) -> bool, reason: &str) -> Vec<KickedPeer> {
+        let targets: Vec<(PeerId, Arc<Notify>, KickedPeer)> = self
+            .peers
+            .iter()
+            .filter(|peer| pred(peer))
+            .map(|peer| {
+                let info = KickedPeer {
+                    username: peer.username.clone(),
+                    addr: peer.addr,
+                    room: peer.room.clone(),
+                };
+                (*peer.key(), peer.kicked.clone(), info)
+            })
+            .collect();
+        let mut kicked = Vec::with_capacity(targets.len());
+        // 通知不等待对方的发送队列，积压的会话也能立即断开
+        for (id, notify, info) in targets {
+            self.try_send_to_peer(&id, Arc::new(Message::system(reason)));
+            notify.notify_one();
+            kicked.push(info);
+        }
+        kicked
+    }
+    // 在被断开会话所在的房间里通知其他成员
+    async fn announce_removed(
+        &self,
+        kicked: &[KickedPeer],
+        action: &str,
+        id: PeerId,
+    ) -> Result<()> {
+        for peer in kicked {
+            let msg = Arc::new(Message::system(&format!(
+                "{} was {}",
+                peer.username, action
+            )));
+            self.broadcast_to_room(&peer.room, msg, id).await?;
+        }
+        Ok(())
+    }
+    // 断开同一用户名的其他会话，返回断开的数量
+    fn logout_others(&self, username: &str, id: PeerId) -> usize {
+        let others: Vec<(PeerId, Arc<Notify>)> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.username == username && peer.key() != &id)
+            .map(|peer| (*peer.key(), peer.kicked.clone()))
+            .collect();
+        for (other, kicked) in &others {
+            let msg = Arc::new(Message::system("logged out by another session"));
+            self.try_send_to_peer(other, msg);
+            kicked.notify_one();
+        }
+        others.len()
+    }
+    // 该用户名当前在线的会话数
+    fn session_count(&self, username: &str) -> usize {
+        self.peers
+            .iter()
+            .filter(|peer| peer.username == username)
+            .count()
+    }
+    // 按用户名私发消息，同名的多个会话都会收到，勿扰模式下的会话先放入队列
+    async fn send_to(
+        &self,
+        target_user: &str,
+        msg: Arc<Message>,
+        receipt: Option<PeerId>,
+    ) -> Delivery {
+        let mut delivery = Delivery::default();
+        let mut targets = Vec::new();
+        for mut peer in self.peers.iter_mut() {
+            if peer.username != target_user {
+                continue;
+            }
+            match &mut peer.dnd {
+                Some(queue) if queue.len() >= DND_QUEUE_SIZE => delivery.dropped += 1,
+                Some(queue) => {
+                    queue.push_back((msg.clone(), receipt));
+                    delivery.queued += 1;
+                }
+                None => targets.push(*peer.key()),
+            }
+        }
+        for id in &targets {
+            if self.try_send_to_peer(id, msg.clone()) {
+                delivery.live += 1;
+            } else {
+                delivery.dropped += 1;
+            }
+        }
+        delivery
+    }
+    // 只向指定的peer发送消息
+    pub async fn send_to_peer(&self, id: &PeerId, msg: Arc<Message>) {
+        let Some(tx) = self.peers.get(id).map(|peer| peer.tx.clone()) else {
+            return;
+        };
+        if let Err(e) = tx.send(msg).await {
+            warn!("Error sending message to {}: {}", id, e);
+        }
+    }
+    // 不等待对方的发送队列：队列满时丢弃，接收端已关闭则移除peer，返回是否送达
+    fn try_send_to_peer(&self, id: &PeerId, msg: Arc<Message>) -> bool {
+        let Some(tx) = self.peers.get(id).map(|peer| peer.tx.clone()) else {
+            return false;
+        };
+        match tx.try_send(msg) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Send queue full, dropping message for {}", id);
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("Send channel closed for {}", id);
+                self.remove_peer(id);
+                false
+            }
+        }
+    }
+    // 向房间内除发送者外的peer广播
+    pub async fn broadcast_to_room(&self, room: &str, msg: Arc<Message>, id: PeerId) -> Result<()> {
+        self.emit_webhook(room, &msg);
+        self.log_transcript(room, &msg).await;
+        // 先复制成员列表，避免持有rooms的锁时再去修改rooms
+        let members: Vec<PeerId> = match self.rooms.get(room) {
+            Some(room) => room.members.iter().map(|member| *member).collect(),
+            None => return Ok(()),
+        };
+        let mut closed = Vec::new();
+        for member in members {
+            let Some(peer) = self.peers.get(&member) else {
+                continue;
+            };
+            // 开启echo的发送者也会收到自己的聊天消息
+            let echo = peer.echo && matches!(*msg, Message::Text { .. });
+            if (peer.key() == &id && !echo) || !peer.filter.accepts(&msg) {
+                continue;
+            }
+            if peer.paused {
+                // 输入状态提示不算错过的消息
+                if !matches!(*msg, Message::Typing(_)) {
+                    peer.missed.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            peer.check_backpressure();
+            // 队列满只丢弃这一条消息，只有接收端已关闭才移除peer
+            match peer.tx.try_send(msg.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Send queue full, dropping message for {}", peer.addr);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    warn!("Send channel closed for {}", peer.addr);
+                    closed.push(*peer.key());
+                }
+            }
+        }
+        // 遍历时持有分片的读锁，移除要在遍历结束后进行
+        for id in closed {
+            self.remove_peer(&id);
+        }
+        Ok(())
+    }
+    // 聊天消息交给后台任务写入文件，队列满时丢弃
+    async fn log_transcript(&self, room: &str, msg: &Arc<Message>) {
+        let wanted = match **msg {
+            Message::Text { .. } => true,
+            Message::Join(_) | Message::Left(_) => self.config.transcript_joins,
+            _ => false,
+        };
+        if !wanted {
+            return;
+        }
+        let transcript = self.transcript.lock().await;
+        let Some((tx, _)) = transcript.as_ref() else {
+            return;
+        };
+        let entry = TranscriptEntry {
+            at: unix_secs(),
+            room: room.to_string(),
+            message: msg.clone(),
+        };
+        if let Err(e) = tx.try_send(entry) {
+            warn!("Drop transcript entry:{}", e);
+        }
+    }
+    // 加入、离开和聊天消息转换成webhook事件，队列满时丢弃
+    fn emit_webhook(&self, room: &str, msg: &Message) {
+        let (Some(tx), Some(config)) = (&self.webhook, &self.config.webhook) else {
+            return;
+        };
+        let room = room.to_string();
+        let event = match msg {
+            Message::Join(user) => WebhookEvent::Join {
+                user: user.clone(),
+                room,
+            },
+            Message::Left(user) => WebhookEvent::Leave {
+                user: user.clone(),
+                room,
+            },
+            Message::Text { user, content, .. } if config.messages => WebhookEvent::Message {
+                user: user.clone(),
+                room,
+                content: content.clone(),
+            },
+            _ => return,
+        };
+        if let Err(e) = tx.try_send(event) {
+            warn!("Drop webhook event:{}", e);
+        }
+    }
+    // 将peer移出peers和所在房间
+    pub fn remove_peer(&self, id: &PeerId) {
+        if let Some((_, handle)) = self.peers.remove(id) {
+            self.leave_room(&handle.room, id);
+        }
+    }
+    fn room(&self, room: &str) -> Option<Arc<Room>> {
+        self.rooms.get(room).map(|room| room.clone())
+    }
+    // 把房间连同成员、历史和投票一起改名；旧房间不存在或新名称已被占用时返回false
+    fn rename_room(&self, old: &str, new: &str) -> bool {
+        if self.rooms.contains_key(new) {
+            return false;
+        }
+        let Some((_, room)) = self.rooms.remove(old) else {
+            return false;
+        };
+        // entry持有分片写锁，放回旧名称要等它释放之后
+        let taken = match self.rooms.entry(new.to_string()) {
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(room.clone());
+                false
+            }
+        };
+        if taken {
+            self.rooms.insert(old.to_string(), room);
+            return false;
+        }
+        for member in room.members.iter() {
+            if let Some(mut handle) = self.peers.get_mut(&*member) {
+                if handle.room == old {
+                    handle.room = new.to_string();
+                }
+            }
+        }
+        // 改名期间断开或换了房间的成员按旧名称离开时找不到房间，这里补上移除
+        room.members
+            .retain(|id| self.peers.get(id).is_some_and(|peer| peer.room == new));
+        true
+    }
+    fn enter_room(&self, room: &str, id: PeerId) {
+        self.rooms
+            .entry(room.to_string())
+            .or_default()
+            .members
+            .insert(id);
+    }
+    // 离开房间，房间为空时连同历史和投票一并移除，默认房间保留
+    // remove_if与enter_room的entry都持有分片写锁，不会删掉刚有人加入的房间
+    fn leave_room(&self, room: &str, id: &PeerId) {
+        if let Some(room) = self.rooms.get(room) {
+            room.members.remove(id);
+        }
+        self.rooms.remove_if(room, |name, room| {
+            name != DEFAULT_ROOM && room.members.is_empty()
+        });
+    }
+    // 将peer从当前房间移到新房间，并分别通知两边的成员
+    async fn switch_room(&self, peer: &mut Peer, room: String) -> Result<()> {
+        let msg = Arc::new(Message::user_left(&peer.username));
+        self.broadcast_to_room(&peer.room, msg, peer.id).await?;
+        self.leave_room(&peer.room, &peer.id);
+        self.enter_room(&room, peer.id);
+        if let Some(mut handle) = self.peers.get_mut(&peer.id) {
+            handle.room = room.clone();
+        }
+        peer.room = room;
+        self.replay_history(&peer.room, &peer.id).await;
+        let msg = Arc::new(Message::user_join(&peer.username));
+        self.broadcast_to_room(&peer.room, msg, peer.id).await
+    }
+    // 通知所有peer服务器即将关闭，并等待发送队列清空，最多等待SHUTDOWN_GRACE
+    pub async fn shutdown(&self) {
+        let msg = Arc::new(Message::system("server shutting down"));
+        for peer in self.peers.iter() {
+            if let Err(e) = peer.tx.try_send(msg.clone()) {
+                warn!("Error sending shutdown notice to {}: {}", peer.addr, e);
+            }
+        }
+        let flushed = time::timeout(SHUTDOWN_GRACE, async {
+            while self.peers.iter().any(|peer| peer.queue_depth() > 0) {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        if flushed.is_err() {
+            warn!("Shutdown with unsent messages");
+        }
+        // 关闭channel后写任务会写完剩余记录并flush
+        if let Some((tx, task)) = self.transcript.lock().await.take() {
+            drop(tx);
+            if let Err(e) = task.await {
+                warn!("Transcript task error:{}", e);
+            }
+        }
+    }
+    // 按房间分配序号并广播聊天消息
+    async fn broadcast_text(
+        &self,
+        room: &str,
+        username: &str,
+        badge: Option<String>,
+        content: String,
+        id: PeerId,
+    ) -> Result<()> {
+        let Some(state) = self.room(room) else {
+            return Ok(());
+        };
+        let mut seq = state.seq.lock().await;
+        *seq += 1;
+        let msg = Arc::new(Message::new_text(*seq, username, badge, content));
+        state.remember(msg.clone()).await;
+        self.broadcast_to_room(room, msg, id).await
+    }
+    // 只向指定peer回放该房间的历史消息
+    async fn replay_history(&self, room: &str, id: &PeerId) {
+        let Some(room) = self.room(room) else {
+            return;
+        };
+        let history: Vec<Arc<Message>> = room.history.lock().await.iter().cloned().collect();
+        for msg in history {
+            self.send_to_peer(id, msg).await;
+        }
+    }
+    // 只向指定peer回放该房间中序号大于after的历史消息，返回回放的条数；
+    // 历史已不足以补齐时同时返回缓冲区中最早的序号
+    async fn resync(&self, room: &str, id: &PeerId, after: u64) -> (usize, Option<u64>) {
+        let Some(room) = self.room(room) else {
+            return (0, None);
+        };
+        let (missed, oldest) = {
+            let history = room.history.lock().await;
+            let missed: Vec<Arc<Message>> = history
+                .iter()
+                .filter(|msg| msg.seq().is_some_and(|seq| seq > after))
+                .cloned()
+                .collect();
+            let oldest = history.front().and_then(|msg| msg.seq());
+            (
+                missed,
+                oldest.filter(|&oldest| oldest > after.saturating_add(1)),
+            )
+        };
+        let count = missed.len();
+        for msg in missed {
+            self.send_to_peer(id, msg).await;
+        }
+        (count, oldest)
+    }
+    // 距上次导出不足EXPORT_INTERVAL时返回还需等待的时长
+    async fn allow_export(&self) -> Result<(), Duration> {
+        let mut last = self.last_export.lock().await;
+        if let Some(wait) = last.and_then(|last| EXPORT_INTERVAL.checked_sub(last.elapsed())) {
+            return Err(wait);
+        }
+        *last = Some(Instant::now());
+        Ok(())
+    }
+    // 把各房间历史消息的快照按房间名、房间内按时间顺序写入文本文件，每行为 房间 消息，超过MAX_EXPORT_BYTES的部分丢弃
+    async fn export_history(&self) -> Result<PathBuf> {
+        let mut rooms: Vec<(String, Arc<Room>)> = self
+            .rooms
+            .iter()
+            .map(|room| (room.key().clone(), room.value().clone()))
+            .collect();
+        rooms.sort_by(|a, b| a.0.cmp(&b.0));
+        // 按房间名依次在各房间的锁内生成快照，写文件时不再持有锁
+        let mut archive = String::new();
+        'rooms: for (name, room) in rooms {
+            for msg in room.history.lock().await.iter() {
+                let line = match msg.seq() {
+                    Some(seq) => format!("{}\t#{} {}\n", name, seq, msg),
+                    None => format!("{}\t{}\n", name, msg),
+                };
+                if archive.len() + line.len() > MAX_EXPORT_BYTES {
+                    break 'rooms;
+                }
+                archive.push_str(&line);
+            }
+        }
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let path = self
+            .config
+            .export_dir
+            .join(format!("chat-export-{}.txt", secs));
+        fs::write(&path, archive).await?;
+        Ok(path)
+    }
+    // stream可以是任意按行收发的Stream+Sink，便于脱离TCP使用
+    pub fn add_peer<S>(self: &Arc<Self>, addr: SocketAddr, username: String, stream: S) -> Peer
+    where
+        S: Stream<Item = Result<String, LinesCodecError>>
+            + Sink<String, Error = LinesCodecError>
+            + Send
+            + 'static,
+    {
+        let (tx, mut rx) = channel::<Arc<Message>>(MSG_SIZE);
+        let kicked = Arc::new(Notify::new());
+        let id = PeerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.peers.insert(
+            id,
+            PeerHandle {
+                tx,
+                addr,
+                username: username.clone(),
+                kicked: kicked.clone(),
+                filter: MessageFilter::default(),
+                echo: self.config.echo,
+                behind: AtomicBool::new(false),
+                room: DEFAULT_ROOM.to_string(),
+                paused: false,
+                missed: AtomicUsize::new(0),
+                dnd: None,
+            },
+        );
+        self.enter_room(DEFAULT_ROOM, id);
+
+        let (mut sender, receiver) = stream.split();
+        let state = Arc::clone(self);
+        let last_seen = Arc::new(Mutex::new(time::Instant::now()));
+        let (writer_seen, writer_kicked) = (last_seen.clone(), kicked.clone());
+        tokio::spawn(async move {
+            let mut heartbeat = state
+                .config
+                .heartbeat
+                .map(|period| time::interval_at(time::Instant::now() + period, period));
+            loop {
+                // rx extract数据之后要修改自身内部状态，所以是mutable
+                let msg = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    period = tick(&mut heartbeat) => {
+                        // 半开连接写入不会立即报错，只能靠客户端长时间没有任何回应来发现
+                        if writer_seen.lock().await.elapsed() > period * HEARTBEAT_MISSES {
+                            info!("Heartbeat timeout for {}", addr);
+                            writer_kicked.notify_one();
+                            break;
+                        }
+                        Arc::new(Message::Ping)
+                    }
+                };
+                let content = state.encode(&msg);
+                if let Err(e) = sender.send(content).await {
+                    // 连接已经不可写，不再继续消费消息，并把peer移出广播列表
+                    warn!("Error sending message to {}: {}", addr, e);
+                    break;
+                }
+            }
+            state.remove_peer(&id);
+        });
+        // receiver是SplitStream，可以异步地接收数据
+        // 不需要mut是因为它是一个异步迭代器，不需要主动修改内部状态
+        Peer {
+            id,
+            username,
+            room: DEFAULT_ROOM.to_string(),
+            stream: receiver.boxed(),
+            last: VecDeque::new(),
+            sent: 0,
+            kicked,
+            last_typing: None,
+            admin: false,
+            last_seen,
+            code: None,
+            bucket: None,
+        }
+    }
+}
+
+impl Room {
+    // 记录聊天消息，超过容量时丢弃最旧的
+    async fn remember(&self, msg: Arc<Message>) {
+        let mut history = self.history.lock().await;
+        if history.len() >= HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(msg);
+    }
+}
+
+impl Peer {
+    // 聊天消息、/me、/share、/poll和代码块都计入会话配额；已用完时返回false
+    fn take_quota(&mut self, quota: Option<usize>) -> bool {
+        if quota.is_some_and(|quota| self.sent >= quota) {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+    // 消耗一个令牌，被限流时返回给发送者的提示
+    fn throttle(&mut self, limit: Option<RateLimit>) -> Option<String> {
+        let limit = limit?;
+        let now = time::Instant::now();
+        let capacity = f64::from(limit.messages);
+        let bucket = self.bucket.get_or_insert(TokenBucket {
+            tokens: capacity,
+            last: now,
+            violations: 0,
+            muted_until: None,
+        });
+        if let Some(until) = bucket.muted_until {
+            if now < until {
+                return Some(format!("muted for {}s", (until - now).as_secs_f64().ceil()));
+            }
+            bucket.muted_until = None;
+        }
+        let refill = now.duration_since(bucket.last).as_secs_f64() / limit.per.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refill * capacity).min(capacity);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.violations = 0;
+            return None;
+        }
+        bucket.violations += 1;
+        if bucket.violations >= MUTE_AFTER_VIOLATIONS {
+            bucket.violations = 0;
+            bucket.muted_until = Some(now + MUTE_DURATION);
+            return Some(format!(
+                "too many messages, muted for {}s",
+                MUTE_DURATION.as_secs()
+            ));
+        }
+        Some("slow down".to_string())
+    }
+    // 向代码块追加一行，超过行数或字节数上限时丢弃整个代码块并返回false
+    fn push_code(&mut self, line: String) -> bool {
+        let Some(lines) = self.code.as_mut() else {
+            return false;
+        };
+        let bytes: usize = lines.iter().map(|l| l.len() + 1).sum();
+        if lines.len() >= MAX_CODE_LINES || bytes + line.len() > MAX_CODE_BYTES {
+            self.code = None;
+            return false;
+        }
+        lines.push(line);
+        true
+    }
+    // 记录自己发送的消息，超过上限时丢弃最旧的
+    fn remember(&mut self, line: &str, size: usize) {
+        if size == 0 {
+            return;
+        }
+        if self.last.len() >= size {
+            self.last.pop_front();
+        }
+        self.last.push_back(line.to_string());
+    }
+}
+impl PeerHandle {
+    // 已入队但写任务尚未写出的消息数
+    fn queue_depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    // 队列即将写满时提醒客户端一次，容量恢复后才会开始新一轮提醒
+    fn check_backpressure(&self) {
+        if self.tx.capacity() >= BACKPRESSURE_THRESHOLD {
+            self.behind.store(false, Ordering::Relaxed);
+            return;
+        }
+        if !self.behind.swap(true, Ordering::Relaxed) {
+            let msg = Arc::new(Message::system("you are falling behind"));
+            if let Err(e) = self.tx.try_send(msg) {
+                warn!("Error sending backpressure notice to {}: {}", self.addr, e);
+            }
+        }
+    }
+}
+
+impl MessageFilter {
+    fn accepts(&self, msg: &Message) -> bool {
+        match self {
+            MessageFilter::All => true,
+            MessageFilter::Text => matches!(
+                msg,
+                Message::Text { .. } | Message::Action { .. } | Message::Code { .. }
+            ),
+        }
+    }
+}
+
+impl Poll {
+    // 解析 "question" opt1 opt2 ...，问题必须用双引号括起来
+    fn parse(args: &str) -> Option<Self> {
+        let rest = args.strip_prefix('"')?;
+        let (question, options) = rest.split_once('"')?;
+        let question = question.trim();
+        let options: Vec<String> = options.split_whitespace().map(String::from).collect();
+        if question.is_empty() || !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+            return None;
+        }
+        Some(Self {
+            question: question.to_string(),
+            options,
+            votes: HashMap::new(),
+        })
+    }
+
+    fn vote(&mut self, username: &str, choice: &str) -> String {
+        match choice.parse::<usize>() {
+            Ok(n) if (1..=self.options.len()).contains(&n) => {
+                self.votes.insert(username.to_string(), n - 1);
+                format!("voted for {}", self.options[n - 1])
+            }
+            _ => format!("usage: /vote <1-{}>", self.options.len()),
+        }
+    }
+
+    fn results(&self) -> String {
+        let mut tally = vec![0; self.options.len()];
+        for choice in self.votes.values() {
+            tally[*choice] += 1;
+        }
+        let results: Vec<String> = self
+            .options
+            .iter()
+            .zip(tally)
+            .enumerate()
+            .map(|(i, (option, count))| format!("{}) {}: {}", i + 1, option, count))
+            .collect();
+        format!("results: {} | {}", self.question, results.join(" "))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+impl fmt::Display for Poll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .enumerate()
+            .map(|(i, option)| format!("{}) {}", i + 1, option))
+            .collect();
+        write!(
+            f,
+            "poll: {} | {} | /vote <n>",
+            self.question,
+            options.join(" ")
+        )
+    }
+}
+
+impl Message {
+    fn user_join(username: &str) -> Self {
+        let username = username.to_string();
+        Message::Join(username)
+    }
+    fn user_left(username: &str) -> Self {
+        let username = username.to_string();
+        Message::Left(username)
+    }
+    fn seq(&self) -> Option<u64> {
+        match self {
+            Message::Text { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+    fn system(content: &str) -> Self {
+        Message::System(content.to_string())
+    }
+    fn new_text(seq: u64, username: &str, badge: Option<String>, content: String) -> Self {
+        let username = username.to_string();
+        Message::Text {
+            seq,
+            user: username,
+            badge,
+            content,
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Join(name) => write!(f, "[{} JOINED]", name),
+            Message::Left(name) => write!(f, "[{} LEFT]", name),
+            Message::System(content) => write!(f, "[SYSTEM]:{}", content),
+            Message::Typing(name) => write!(f, "[{} is typing...]", name),
+            Message::Ping => write!(f, "[PING]"),
+            Message::Rename { old, new } => write!(f, "[{} is now known as {}]", old, new),
+            Message::Action { user, content } => write!(f, "* {} {}", user, content),
+            Message::Code { user, content } => write!(f, "[{}]:\n```\n{}\n```", user, content),
+            Message::Direct { from, to, content } => {
+                write!(f, "[{} -> {}]:{}", from, to, content)
+            }
+            Message::Text {
+                user,
+                badge: Some(badge),
+                content,
+                ..
+            } => write!(f, "[{}][{}]:{}", badge, user, content),
+            Message::Text {
+                user,
+                badge: None,
+                content,
+                ..
+            } => write!(f, "[{}]:{}", user, content),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tokio::io::{duplex, DuplexStream};
+
+    // 测试中等待一条消息的最长时间
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // 通过内存管道连接到handle_connection的测试客户端
+    struct Client {
+        stream: Framed<DuplexStream, LinesCodec>,
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, n], 40000))
+    }
+
+    fn state(config: ChatConfig) -> Arc<ChatState> {
+        Arc::new(ChatState::new(config))
+    }
+
+    fn connect(state: &Arc<ChatState>, addr: SocketAddr) -> Client {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, addr, state.clone()));
+        Client {
+            stream: Framed::new(client, LinesCodec::new()),
+        }
+    }
+
+    // 连接并登录，返回时已加入默认房间
+    async fn login(state: &Arc<ChatState>, name: &str) -> Client {
+        let mut client = connect(state, addr(1));
+        assert_eq!(client.system().await, "Enter your name:");
+        client.send(name).await;
+        client.sync().await;
+        client
+    }
+
+    impl Client {
+        async fn send(&mut self, line: &str) {
+            self.stream.send(line.to_string()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Message {
+            let line = time::timeout(RECV_TIMEOUT, self.stream.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("connection closed")
+                .unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        async fn system(&mut self) -> String {
+            match self.recv().await {
+                Message::System(content) => content,
+                msg => panic!("expected a system message, got {:?}", msg),
+            }
+        }
+
+        // 发送一个只回复给自己且没有副作用的命令，返回在回复之前收到的消息
+        async fn sync(&mut self) -> Vec<Message> {
+            self.send("/endcode").await;
+            let mut received = Vec::new();
+            loop {
+                match self.recv().await {
+                    Message::System(content) if content == "no code block started" => {
+                        return received
+                    }
+                    msg => received.push(msg),
+                }
+            }
+        }
+
+        // 发送命令并返回回复
+        async fn command(&mut self, line: &str) -> String {
+            self.send(line).await;
+            self.system().await
+        }
+
+        // 等待服务器关闭连接，返回关闭前收到的消息
+        async fn closed(&mut self) -> Vec<Message> {
+            let mut received = Vec::new();
+            loop {
+                match time::timeout(RECV_TIMEOUT, self.stream.next())
+                    .await
+                    .expect("timed out waiting for the connection to close")
+                {
+                    Some(Ok(line)) => received.push(serde_json::from_str(&line).unwrap()),
+                    _ => return received,
+                }
+            }
+        }
+    }
+
+    fn text(msg: &Message) -> Option<(u64, &str, &str)> {
+        match msg {
+            Message::Text {
+                seq, user, content, ..
+            } => Some((*seq, user.as_str(), content.as_str())),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_other_peers_and_leave_removes_peer() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        assert!(matches!(alice.recv().await, Message::Join(name) if name == "bob"));
+
+        bob.send("hello").await;
+        assert_eq!(text(&alice.recv().await), Some((1, "bob", "hello")));
+        assert_eq!(text(&bob.recv().await), Some((1, "bob", "hello")));
+
+        bob.send("/quit").await;
+        assert!(matches!(bob.closed().await.as_slice(), [Message::System(bye)] if bye == "bye"));
+        assert!(matches!(alice.recv().await, Message::Left(name) if name == "bob"));
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(*state.roster.read().await, vec!["alice"]);
+    }
+
+    #[tokio::test]
+    async fn add_peer_accepts_any_line_stream() {
+        let state = state(ChatConfig::default());
+        let (client, server) = duplex(1024);
+        let mut peer = state.add_peer(
+            addr(1),
+            "alice".to_string(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        client.send("hi".to_string()).await.unwrap();
+        assert_eq!(peer.stream.next().await.unwrap().unwrap(), "hi");
+        state
+            .send_to_peer(&peer.id, Arc::new(Message::system("welcome")))
+            .await;
+        let line = client.next().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"type":"system","data":"welcome"}"#);
+
+        // 写任务在peer移除后结束，读半边随Peer释放
+        state.remove_peer(&peer.id);
+        drop(peer);
+        assert!(state.peers.is_empty());
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn control_chars_are_stripped_from_broadcasts() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.send("\x1b[31mred\x1b[0m\tok").await;
+        assert_eq!(
+            text(&alice.recv().await),
+            Some((1, "bob", "[31mred[0m\tok"))
+        );
+    }
+
+    #[tokio::test]
+    async fn control_chars_reject_the_message_in_reject_mode() {
+        let state = state(ChatConfig {
+            sanitize: SanitizeMode::Reject,
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        assert_eq!(
+            alice.command("\x1b[2Jhi").await,
+            "message rejected: control characters are not allowed"
+        );
+        assert!(state
+            .room(DEFAULT_ROOM)
+            .unwrap()
+            .history
+            .lock()
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn control_chars_are_not_allowed_in_names() {
+        let state = state(ChatConfig::default());
+        let mut client = connect(&state, addr(1));
+        client.system().await;
+        client.send("bad\x07name").await;
+        assert_eq!(
+            client.system().await,
+            "Name can't contain control characters, choose another:"
+        );
+        client.send("alice").await;
+        client.sync().await;
+
+        // JSON转义的控制字符在解码后剔除
+        client.send(r#"{"cmd":"nick","args":"ca\u001brl"}"#).await;
+        assert!(matches!(client.recv().await, Message::Rename { new, .. } if new == "carl"));
+        assert_eq!(client.command("/join ro\x1bom").await, "joined room");
+    }
+
+    #[tokio::test]
+    async fn last_returns_the_previous_message() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        assert_eq!(alice.command("/last").await, "no previous message");
+
+        alice.send("first").await;
+        alice.send("second").await;
+        assert_eq!(alice.sync().await.len(), 2);
+        assert_eq!(alice.command("/last").await, "last: second");
+    }
+
+    #[tokio::test]
+    async fn switching_rooms_notifies_both_rooms() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut carol = login(&state, "carol").await;
+        alice.recv().await;
+        assert_eq!(carol.command("/join dev").await, "joined dev");
+        assert!(matches!(alice.recv().await, Message::Left(name) if name == "carol"));
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        assert_eq!(bob.command("/join dev").await, "joined dev");
+        assert!(matches!(alice.recv().await, Message::Left(name) if name == "bob"));
+        assert!(matches!(carol.recv().await, Message::Join(name) if name == "bob"));
+
+        assert_eq!(bob.command("/leave").await, "back in lobby");
+        assert!(matches!(carol.recv().await, Message::Left(name) if name == "bob"));
+        assert!(matches!(alice.recv().await, Message::Join(name) if name == "bob"));
+    }
+
+    #[tokio::test]
+    async fn poll_results_tally_votes() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        let poll = alice.command(r#"/poll "lunch?" pizza sushi"#).await;
+        assert_eq!(poll, "poll: lunch? | 1) pizza 2) sushi | /vote <n>");
+        assert_eq!(bob.system().await, poll);
+
+        assert_eq!(alice.command("/vote 2").await, "voted for sushi");
+        assert_eq!(bob.command("/vote 1").await, "voted for pizza");
+        // 重复投票覆盖之前的选择
+        assert_eq!(bob.command("/vote 2").await, "voted for sushi");
+        assert_eq!(bob.command("/vote 3").await, "usage: /vote <1-2>");
+        assert_eq!(
+            alice.command("/results").await,
+            "results: lunch? | 1) pizza: 0 2) sushi: 2"
+        );
+    }
+
+    #[test]
+    fn poll_requires_a_quoted_question_and_options() {
+        assert!(Poll::parse("lunch? pizza sushi").is_none());
+        assert!(Poll::parse(r#""lunch?" pizza"#).is_none());
+        assert!(Poll::parse(r#""" pizza sushi"#).is_none());
+        let options = ["x"; MAX_POLL_OPTIONS + 1].join(" ");
+        assert!(Poll::parse(&format!(r#""q" {}"#, options)).is_none());
+        let poll = Poll::parse(r#""lunch ?"  pizza   sushi"#).unwrap();
+        assert_eq!(poll.question, "lunch ?");
+        assert_eq!(poll.options, vec!["pizza", "sushi"]);
+    }
+
+    #[tokio::test]
+    async fn roster_matches_peers_after_joins_and_leaves() {
+        let state = state(ChatConfig::default());
+        let mut clients = Vec::new();
+        for name in ["dave", "alice", "carol", "bob", "erin"] {
+            clients.push(login(&state, name).await);
+        }
+        clients[1].send("/quit").await;
+        clients[1].closed().await;
+        clients[3].sync().await;
+        clients[3].send("/nick zed").await;
+        assert!(matches!(clients[3].recv().await, Message::Rename { .. }));
+        drop(clients.pop());
+        // erin直接断开，等其他人收到离开通知
+        loop {
+            if matches!(clients[0].recv().await, Message::Left(name) if name == "erin") {
+                break;
+            }
+        }
+
+        let mut names: Vec<String> = state.peers.iter().map(|p| p.username.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec!["carol", "dave", "zed"]);
+        assert_eq!(*state.roster.read().await, names);
+        assert_eq!(
+            clients[0].command("/who").await,
+            "online(3): carol, dave, zed"
+        );
+    }
+
+    #[tokio::test]
+    async fn text_filter_only_receives_chat_messages() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+        assert_eq!(bob.command("/filter text").await, "filter set to text");
+
+        let mut carol = login(&state, "carol").await;
+        carol.send("/me waves").await;
+        carol.send("hi").await;
+        carol.send("/quit").await;
+        carol.closed().await;
+
+        assert!(matches!(alice.recv().await, Message::Join(_)));
+        assert!(matches!(alice.recv().await, Message::Action { .. }));
+        assert!(text(&alice.recv().await).is_some());
+        assert!(matches!(alice.recv().await, Message::Left(_)));
+        let received = bob.sync().await;
+        assert!(matches!(received[0], Message::Action { .. }));
+        assert_eq!(text(&received[1]), Some((1, "carol", "hi")));
+        assert_eq!(received.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn session_quota_rejects_messages_beyond_it() {
+        let state = state(ChatConfig {
+            session_quota: Some(3),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.send("one").await;
+        bob.send("/me two").await;
+        bob.send("/share https://example.com/a three").await;
+        assert_eq!(bob.sync().await.len(), 3);
+        assert_eq!(bob.command("four").await, QUOTA_REACHED);
+        assert_eq!(bob.command("/me five").await, QUOTA_REACHED);
+        assert_eq!(bob.command(r#"/poll "q" a b"#).await, QUOTA_REACHED);
+        // 不广播的命令不受配额限制
+        assert_eq!(bob.command("/last").await, "last: one");
+
+        assert_eq!(alice.sync().await.len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn history_order_is_kept_under_concurrent_broadcasts() {
+        let state = state(ChatConfig::default());
+        let mut tasks = Vec::new();
+        for i in 0..4 {
+            let mut client = login(&state, &format!("user{}", i)).await;
+            tasks.push(tokio::spawn(async move {
+                for n in 0..10 {
+                    client.send(&format!("{}", n)).await;
+                }
+                client.sync().await;
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let room = state.room(DEFAULT_ROOM).unwrap();
+        let history = room.history.lock().await;
+        let seqs: Vec<u64> = history.iter().filter_map(|msg| msg.seq()).collect();
+        assert_eq!(seqs, (1..=40).collect::<Vec<u64>>());
+        // 同一用户的消息保持发送顺序
+        for i in 0..4 {
+            let user = format!("user{}", i);
+            let contents: Vec<&str> = history
+                .iter()
+                .filter_map(|msg| text(msg))
+                .filter(|(_, u, _)| *u == user)
+                .map(|(_, _, content)| content)
+                .collect();
+            assert_eq!(contents, (0..10).map(|n| n.to_string()).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_controls_whether_the_sender_gets_its_own_message() {
+        let quiet = state(ChatConfig {
+            echo: false,
+            ..Default::default()
+        });
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        alice.send("mine").await;
+        assert_eq!(text(&alice.recv().await), Some((1, "alice", "mine")));
+
+        assert_eq!(alice.command("/echo off").await, "echo off");
+        alice.send("quiet").await;
+        assert!(alice.sync().await.is_empty());
+
+        let mut bob = login(&quiet, "bob").await;
+        bob.send("quiet").await;
+        assert!(bob.sync().await.is_empty());
+        assert_eq!(bob.command("/echo on").await, "echo on");
+        bob.send("loud").await;
+        assert_eq!(text(&bob.recv().await), Some((2, "bob", "loud")));
+    }
+
+    #[tokio::test]
+    async fn write_error_removes_the_peer() {
+        let state = state(ChatConfig::default());
+        let (client, server) = duplex(1024);
+        let peer = state.add_peer(
+            addr(1),
+            "alice".to_string(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        drop(client);
+
+        state
+            .send_to_peer(&peer.id, Arc::new(Message::system("lost")))
+            .await;
+        time::timeout(RECV_TIMEOUT, async {
+            while state.peers.contains_key(&peer.id) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer was not removed");
+        assert!(state.room(DEFAULT_ROOM).unwrap().members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn logout_others_keeps_only_the_requester() {
+        let state = state(ChatConfig {
+            unique_names: false,
+            ..Default::default()
+        });
+        let mut old = login(&state, "alice").await;
+        let mut new = login(&state, "alice").await;
+        old.recv().await;
+
+        assert_eq!(
+            new.command("/logout-others").await,
+            "disconnected 1 other session(s)"
+        );
+        let received = old.closed().await;
+        assert!(
+            matches!(received.as_slice(), [Message::System(m)] if m == "logged out by another session")
+        );
+        assert!(matches!(new.recv().await, Message::Left(name) if name == "alice"));
+        assert_eq!(state.session_count("alice"), 1);
+        assert_eq!(
+            new.command("/logout-others").await,
+            "disconnected 0 other session(s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_get_distinct_addressable_ids() {
+        let state = state(ChatConfig::default());
+        let (a, a_server) = duplex(1024);
+        let (b, b_server) = duplex(1024);
+        // 同一个地址的两个连接也是不同的peer
+        let alice = state.add_peer(
+            addr(1),
+            "alice".into(),
+            Framed::new(a_server, LinesCodec::new()),
+        );
+        let bob = state.add_peer(
+            addr(1),
+            "bob".into(),
+            Framed::new(b_server, LinesCodec::new()),
+        );
+        assert_ne!(alice.id, bob.id);
+        assert_eq!(state.peers.len(), 2);
+
+        let (mut a, mut b) = (
+            Framed::new(a, LinesCodec::new()),
+            Framed::new(b, LinesCodec::new()),
+        );
+        state
+            .send_to_peer(&bob.id, Arc::new(Message::system("for bob")))
+            .await;
+        state
+            .send_to_peer(&alice.id, Arc::new(Message::system("for alice")))
+            .await;
+        assert_eq!(
+            b.next().await.unwrap().unwrap(),
+            r#"{"type":"system","data":"for bob"}"#
+        );
+        assert_eq!(
+            a.next().await.unwrap().unwrap(),
+            r#"{"type":"system","data":"for alice"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn typing_is_broadcast_rate_limited_and_not_kept() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.send("/typing").await;
+        bob.send("/typing").await;
+        bob.sync().await;
+        assert!(matches!(alice.recv().await, Message::Typing(name) if name == "bob"));
+        // 第二次在TYPING_INTERVAL内，被忽略
+        assert!(alice.sync().await.is_empty());
+        assert!(state
+            .room(DEFAULT_ROOM)
+            .unwrap()
+            .history
+            .lock()
+            .await
+            .is_empty());
+    }
+
+    // 向房间广播n条消息，发送者不在房间内
+    async fn flood(state: &ChatState, n: usize) {
+        for i in 0..n {
+            let msg = Arc::new(Message::system(&i.to_string()));
+            state
+                .broadcast_to_room(DEFAULT_ROOM, msg, PeerId(0))
+                .await
+                .unwrap();
+        }
+    }
+
+    // 读出写任务积压的所有消息，返回其中积压提醒的条数
+    async fn drain(client: &mut Framed<DuplexStream, LinesCodec>) -> usize {
+        let mut notices = 0;
+        while let Ok(Some(line)) = time::timeout(Duration::from_millis(200), client.next()).await {
+            if line.unwrap().contains("you are falling behind") {
+                notices += 1;
+            }
+        }
+        notices
+    }
+
+    #[tokio::test]
+    async fn backpressure_notice_is_sent_once_per_episode() {
+        let state = state(ChatConfig::default());
+        // 很小的管道让写任务很快阻塞，消息积压在发送队列里
+        let (client, server) = duplex(64);
+        let _peer = state.add_peer(
+            addr(1),
+            "alice".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        flood(&state, MSG_SIZE).await;
+        assert_eq!(drain(&mut client).await, 1);
+        flood(&state, MSG_SIZE).await;
+        assert_eq!(drain(&mut client).await, 1);
+    }
+
+    fn roles() -> HashMap<String, Role> {
+        let role = Role {
+            badge: "MOD".to_string(),
+            password: "secret".to_string(),
+        };
+        HashMap::from([("mia".to_string(), role)])
+    }
+
+    // 以角色用户名登录，需要输入角色密码
+    async fn login_role(state: &Arc<ChatState>, name: &str, password: &str) -> Client {
+        let mut client = connect(state, addr(1));
+        client.system().await;
+        client.send(name).await;
+        assert_eq!(
+            client.system().await,
+            format!("Enter password for {}:", name)
+        );
+        client.send(password).await;
+        client.sync().await;
+        client
+    }
+
+    #[tokio::test]
+    async fn role_badge_is_shown_on_messages_and_roster() {
+        let state = state(ChatConfig {
+            roles: roles(),
+            ..Default::default()
+        });
+        let mut mia = login_role(&state, "mia", "secret").await;
+        let mut bob = login(&state, "bob").await;
+        mia.recv().await;
+
+        mia.send("hello").await;
+        for client in [&mut mia, &mut bob] {
+            assert!(
+                matches!(client.recv().await, Message::Text { badge: Some(b), .. } if b == "MOD")
+            );
+        }
+        bob.send("hi").await;
+        for client in [&mut mia, &mut bob] {
+            assert!(matches!(
+                client.recv().await,
+                Message::Text { badge: None, .. }
+            ));
+        }
+        assert_eq!(bob.command("/who").await, "online(2): bob, [MOD]mia");
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_increase_within_each_room() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        for line in ["a", "b", "c"] {
+            alice.send(line).await;
+        }
+        alice.sync().await;
+        for line in ["a", "b", "c"] {
+            bob.send(line).await;
+        }
+        let seqs: Vec<u64> = bob.sync().await.iter().filter_map(Message::seq).collect();
+        assert_eq!(seqs, (1..=6).collect::<Vec<u64>>());
+        alice.sync().await;
+
+        // 新房间从1开始编号，默认房间继续递增
+        alice.command("/join dev").await;
+        alice.send("x").await;
+        bob.send("y").await;
+        let received = alice.sync().await;
+        assert_eq!(text(&received[0]), Some((1, "alice", "x")));
+        let received = bob.sync().await;
+        assert!(matches!(&received[0], Message::Left(name) if name == "alice"));
+        assert_eq!(text(&received[1]), Some((7, "bob", "y")));
+    }
+
+    #[test]
+    fn parse_command_handles_whitespace_and_empty_args() {
+        assert!(parse_command("hello").is_none());
+        assert!(parse_command("hi /nick bob").is_none());
+        assert!(parse_command("").is_none());
+        assert!(matches!(parse_command("/nick bob"), Some(Command::Nick(n)) if n == "bob"));
+        assert!(matches!(parse_command("  /nick  bob  "), Some(Command::Nick(n)) if n == "bob"));
+        assert!(matches!(parse_command("\t/who"), Some(Command::Who)));
+        assert!(matches!(parse_command("/nick"), Some(Command::Nick(n)) if n.is_empty()));
+        assert!(matches!(parse_command("/nick   "), Some(Command::Nick(n)) if n.is_empty()));
+        assert!(
+            matches!(parse_command("/me  waves  twice "), Some(Command::Me(a)) if a == "waves  twice")
+        );
+        assert!(matches!(parse_command("/quit now"), Some(Command::Quit)));
+        assert!(matches!(parse_command("/"), Some(Command::Unknown(c)) if c.is_empty()));
+        assert!(matches!(parse_command("/NICK bob"), Some(Command::Unknown(c)) if c == "NICK"));
+    }
+
+    #[test]
+    fn parse_command_accepts_json_envelopes() {
+        assert!(matches!(
+            parse_command(r#"{"cmd":"who"}"#),
+            Some(Command::Who)
+        ));
+        assert!(
+            matches!(parse_command(r#"{"cmd":"nick","args":" bob "}"#), Some(Command::Nick(n)) if n == "bob")
+        );
+        assert!(
+            matches!(parse_command(r#"{"cmd":"me","args":""}"#), Some(Command::Me(a)) if a.is_empty())
+        );
+        // 不是命令格式的JSON按普通消息处理
+        assert!(parse_command(r#"{"text":"hi"}"#).is_none());
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_session_cap_are_rejected() {
+        let state = state(ChatConfig {
+            unique_names: false,
+            max_sessions: Some(2),
+            ..Default::default()
+        });
+        let _first = login(&state, "alice").await;
+        let mut second = login(&state, "alice").await;
+
+        let mut third = connect(&state, addr(1));
+        third.system().await;
+        third.send("alice").await;
+        let received = third.closed().await;
+        assert!(
+            matches!(received.as_slice(), [Message::System(m)] if m == "Too many connections for this name")
+        );
+        assert_eq!(state.session_count("alice"), 2);
+        assert_eq!(*state.roster.read().await, vec!["alice", "alice"]);
+
+        // 改名同样受上限约束
+        let mut bob = login(&state, "bob").await;
+        second.recv().await;
+        assert_eq!(
+            bob.command("/nick alice").await,
+            "too many connections for alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn acquire_username_retries_until_a_free_name() {
+        let state = state(ChatConfig::default());
+        assert!(state.roster_add("alice").await);
+        let (client, server) = duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let mut server = Framed::new(server, LinesCodec::new());
+
+        for line in ["", "  alice ", "admin", "bob"] {
+            client.send(line.to_string()).await.unwrap();
+        }
+        let name = acquire_username(&mut server, &state).await.unwrap();
+        assert_eq!(name.as_deref(), Some("bob"));
+        let mut prompts = Vec::new();
+        for _ in 0..4 {
+            let line = client.next().await.unwrap().unwrap();
+            match serde_json::from_str(&line).unwrap() {
+                Message::System(prompt) => prompts.push(prompt),
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        assert_eq!(
+            prompts,
+            vec![
+                "Enter your name:",
+                "Name can't be empty, choose another:",
+                "Name taken, choose another:",
+                "Name is reserved or not allowed, choose another:",
+            ]
+        );
+        assert_eq!(*state.roster.read().await, vec!["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_during_login_leaves_no_entry() {
+        let state = state(ChatConfig {
+            join_password: Some("letmein".to_string()),
+            ..Default::default()
+        });
+        // 输入用户名之前断开
+        let mut client = connect(&state, addr(1));
+        client.system().await;
+        drop(client);
+
+        // 输入用户名之后、密码之前断开
+        let (client, server) = duplex(1024);
+        let task = tokio::spawn(handle_connection(server, addr(2), state.clone()));
+        let mut client = Client {
+            stream: Framed::new(client, LinesCodec::new()),
+        };
+        client.system().await;
+        client.send("alice").await;
+        assert_eq!(client.system().await, "Enter password:");
+        drop(client);
+        // 连接已断开，回复密码错误时写入失败
+        let _ = task.await.unwrap();
+
+        assert!(state.roster.read().await.is_empty());
+        assert!(state.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_peer_receives_the_most_recent_history() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        for n in 1..=HISTORY_SIZE + 10 {
+            alice.send(&format!("message {}", n)).await;
+        }
+        alice.sync().await;
+
+        let mut bob = connect(&state, addr(2));
+        bob.system().await;
+        bob.send("bob").await;
+        let history = bob.sync().await;
+        let contents: Vec<String> = history
+            .iter()
+            .map(|msg| text(msg).unwrap().2.to_string())
+            .collect();
+        let expected: Vec<String> = (11..=HISTORY_SIZE + 10)
+            .map(|n| format!("message {}", n))
+            .collect();
+        assert_eq!(contents, expected);
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_messages_but_closed_queue_drops_the_peer() {
+        let state = state(ChatConfig::default());
+        // 客户端不读取，写任务阻塞后消息积压在发送队列里
+        let (client, server) = duplex(64);
+        let peer = state.add_peer(
+            addr(1),
+            "alice".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        flood(&state, MSG_SIZE * 2).await;
+        assert!(state.peers.contains_key(&peer.id));
+
+        // 接收端关闭后下一次广播移除peer
+        let (tx, rx) = channel(1);
+        drop(rx);
+        state.peers.get_mut(&peer.id).unwrap().tx = tx;
+        flood(&state, 1).await;
+        assert!(!state.peers.contains_key(&peer.id));
+        assert!(state.room(DEFAULT_ROOM).unwrap().members.is_empty());
+        drop(client);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_peer_is_reaped_while_active_peer_survives() {
+        let state = state(ChatConfig {
+            idle_timeout: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let mut idle = login(&state, "idle").await;
+        let mut active = login(&state, "active").await;
+
+        time::sleep(Duration::from_secs(40)).await;
+        active.send("still here").await;
+        time::sleep(Duration::from_secs(40)).await;
+
+        let received = idle.closed().await;
+        assert!(
+            matches!(received.last(), Some(Message::System(m)) if m == "disconnected due to inactivity")
+        );
+        let received = active.sync().await;
+        assert!(matches!(received.last(), Some(Message::Left(name)) if name == "idle"));
+        assert_eq!(state.peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn join_password_admits_only_the_right_password() {
+        let state = state(ChatConfig {
+            join_password: Some("letmein".to_string()),
+            ..Default::default()
+        });
+        let mut alice = connect(&state, addr(1));
+        alice.system().await;
+        alice.send("alice").await;
+        assert_eq!(alice.system().await, "Enter password:");
+        alice.send("wrong").await;
+        assert_eq!(alice.system().await, "Wrong password, try again:");
+        alice.send("letmein").await;
+        alice.sync().await;
+        assert_eq!(state.session_count("alice"), 1);
+
+        let mut mallory = connect(&state, addr(2));
+        mallory.system().await;
+        mallory.send("mallory").await;
+        mallory.system().await;
+        for _ in 1..MAX_PASSWORD_ATTEMPTS {
+            mallory.send("guess").await;
+            mallory.system().await;
+        }
+        mallory.send("guess").await;
+        let received = mallory.closed().await;
+        assert!(matches!(received.as_slice(), [Message::System(m)] if m == "Wrong password"));
+        assert_eq!(*state.roster.read().await, vec!["alice"]);
+    }
+
+    #[tokio::test]
+    async fn over_long_line_is_reported_and_the_connection_stays_open() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.send(&"x".repeat(MSG_SIZE * 3)).await;
+        assert_eq!(
+            bob.system().await,
+            format!("message too long, max {} bytes", MSG_SIZE)
+        );
+        bob.send("short").await;
+        assert_eq!(text(&alice.recv().await), Some((1, "bob", "short")));
+        assert!(state.room(DEFAULT_ROOM).unwrap().history.lock().await.len() == 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_peers() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        state.shutdown().await;
+        assert_eq!(alice.system().await, "server shutting down");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_finishes_when_a_peer_queue_is_stuck() {
+        let state = state(ChatConfig::default());
+        let (_client, server) = duplex(64);
+        let _peer = state.add_peer(
+            addr(1),
+            "slow".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        flood(&state, MSG_SIZE * 2).await;
+
+        let started = time::Instant::now();
+        time::timeout(SHUTDOWN_GRACE * 2, state.shutdown())
+            .await
+            .expect("shutdown did not finish");
+        assert!(started.elapsed() <= SHUTDOWN_GRACE + Duration::from_millis(100));
+    }
+
+    // 每个测试使用独立的临时目录
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("chat-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn export_writes_history_in_order() {
+        let dir = temp_dir("export");
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            export_dir: dir.clone(),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        assert_eq!(alice.command("/export").await, "permission denied");
+        assert_eq!(alice.command("/auth nope").await, "invalid token");
+        assert_eq!(alice.command("/auth token").await, "authenticated as admin");
+
+        for line in ["one", "two"] {
+            alice.send(line).await;
+        }
+        alice.sync().await;
+        alice.command("/join dev").await;
+        alice.send("three").await;
+        alice.sync().await;
+        let reply = alice.command("/export").await;
+        let path = reply.strip_prefix("history exported to ").unwrap();
+        assert!(path.starts_with(dir.to_str().unwrap()));
+        let archive = std::fs::read_to_string(path).unwrap();
+        assert_eq!(
+            archive,
+            "dev\t#1 [alice]:three\nlobby\t#1 [alice]:one\nlobby\t#2 [alice]:two\n"
+        );
+
+        let reply = alice.command("/export").await;
+        assert!(reply.starts_with("export rate limited"), "{}", reply);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_message_variant_round_trips_through_json() {
+        let messages = vec![
+            (
+                Message::Join("alice".into()),
+                r#"{"type":"join","data":"alice"}"#,
+            ),
+            (
+                Message::Left("alice".into()),
+                r#"{"type":"left","data":"alice"}"#,
+            ),
+            (Message::system("hi"), r#"{"type":"system","data":"hi"}"#),
+            (
+                Message::Typing("alice".into()),
+                r#"{"type":"typing","data":"alice"}"#,
+            ),
+            (Message::Ping, r#"{"type":"ping"}"#),
+            (
+                Message::Rename {
+                    old: "alice".into(),
+                    new: "bob".into(),
+                },
+                r#"{"type":"rename","data":{"old":"alice","new":"bob"}}"#,
+            ),
+            (
+                Message::Direct {
+                    from: "alice".into(),
+                    to: "bob".into(),
+                    content: "hi".into(),
+                },
+                r#"{"type":"direct","data":{"from":"alice","to":"bob","content":"hi"}}"#,
+            ),
+            (
+                Message::Action {
+                    user: "alice".into(),
+                    content: "waves".into(),
+                },
+                r#"{"type":"action","data":{"user":"alice","content":"waves"}}"#,
+            ),
+            (
+                Message::Code {
+                    user: "alice".into(),
+                    content: "fn main() {\n}".into(),
+                },
+                r#"{"type":"code","data":{"user":"alice","content":"fn main() {\n}"}}"#,
+            ),
+            (
+                Message::new_text(1, "alice", None, "hi".into()),
+                r#"{"type":"text","data":{"seq":1,"user":"alice","badge":null,"content":"hi"}}"#,
+            ),
+            (
+                Message::new_text(2, "alice", Some("MOD".into()), "hi".into()),
+                r#"{"type":"text","data":{"seq":2,"user":"alice","badge":"MOD","content":"hi"}}"#,
+            ),
+        ];
+        for (msg, json) in messages {
+            assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+            let decoded: Message = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn text_protocol_encodes_plain_lines() {
+        let state = ChatState::new(ChatConfig {
+            protocol: Protocol::Text,
+            show_seq: true,
+            ..Default::default()
+        });
+        let msg = Message::new_text(3, "alice", Some("MOD".into()), "hi".into());
+        assert_eq!(state.encode(&msg), "#3 [MOD][alice]:hi");
+        assert_eq!(state.encode(&Message::Join("bob".into())), "[bob JOINED]");
+        assert_eq!(state.prompt("Enter your name:"), "Enter your name:");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_is_reaped_by_heartbeat() {
+        let state = state(ChatConfig {
+            heartbeat: Some(Duration::from_secs(10)),
+            ..Default::default()
+        });
+        let mut silent = login(&state, "silent").await;
+        let mut alive = login(&state, "alive").await;
+        // 对每个心跳回复/pong
+        let responder = tokio::spawn(async move {
+            while let Some(Ok(line)) = alive.stream.next().await {
+                if matches!(serde_json::from_str(&line), Ok(Message::Ping)) {
+                    alive.send("/pong").await;
+                }
+            }
+        });
+
+        time::sleep(Duration::from_secs(10) * (HEARTBEAT_MISSES + 2)).await;
+        let received = silent.closed().await;
+        assert!(received.iter().any(|msg| matches!(msg, Message::Ping)));
+        assert_eq!(state.session_count("silent"), 0);
+        assert_eq!(state.session_count("alive"), 1);
+        responder.abort();
+    }
+
+    #[tokio::test]
+    async fn code_block_is_broadcast_as_one_message() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        assert_eq!(
+            bob.command("/code").await,
+            "code mode: send /endcode to finish"
+        );
+        for line in ["fn main() {", "    /who", "}"] {
+            bob.send(line).await;
+        }
+        bob.send("/endcode").await;
+        let expected = "fn main() {\n    /who\n}";
+        for client in [&mut alice, &mut bob] {
+            match client.recv().await {
+                Message::Code { user, content } => {
+                    assert_eq!(user, "bob");
+                    assert_eq!(content, expected);
+                }
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        bob.send("/code").await;
+        assert_eq!(
+            bob.command("/endcode").await,
+            "code mode: send /endcode to finish"
+        );
+    }
+
+    #[tokio::test]
+    async fn echo_keeps_order_with_other_peers_messages() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.send("first").await;
+        bob.sync().await;
+        alice.send("second").await;
+        bob.send("third").await;
+        bob.sync().await;
+        let received = alice.sync().await;
+        let seqs: Vec<u64> = received.iter().filter_map(Message::seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn pause_counts_missed_messages() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        bob.command("/pause").await;
+        for line in ["a", "b", "c"] {
+            alice.send(line).await;
+        }
+        alice.send("/typing").await;
+        alice.sync().await;
+        assert_eq!(
+            bob.command("/resume").await,
+            "resumed, you missed 3 message(s)"
+        );
+        assert_eq!(bob.command("/resume").await, "not paused");
+        alice.send("d").await;
+        assert_eq!(text(&bob.recv().await), Some((4, "alice", "d")));
+    }
+
+    #[tokio::test]
+    async fn banned_address_is_rejected_before_login() {
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let mut admin = login(&state, "root").await;
+        admin.command("/auth token").await;
+        let mut mallory = connect(&state, addr(9));
+        mallory.system().await;
+        mallory.send("mallory").await;
+        mallory.sync().await;
+        admin.sync().await;
+
+        assert_eq!(admin.command("/ban mallory").await, "banned mallory");
+        assert!(mallory
+            .closed()
+            .await
+            .iter()
+            .any(|msg| matches!(msg, Message::System(m) if m == "you were banned")));
+        // 被断开的会话离开时会广播离开通知
+        assert!(matches!(admin.recv().await, Message::Left(name) if name == "mallory"));
+
+        let mut again = connect(&state, addr(9));
+        let received = again.closed().await;
+        assert!(matches!(received.as_slice(), [Message::System(m)] if m == "You are banned"));
+        assert!(!state.roster.read().await.contains(&"mallory".to_string()));
+        // 其他地址不受影响
+        let _bob = login(&state, "bob").await;
+        admin.recv().await;
+
+        assert_eq!(admin.command("/ban 127.0.0.8").await, "banned 127.0.0.8");
+        let received = connect(&state, addr(8)).closed().await;
+        assert!(matches!(received.as_slice(), [Message::System(m)] if m == "You are banned"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_rejects_a_burst_and_refills_over_time() {
+        let state = state(ChatConfig {
+            rate_limit: Some(RateLimit {
+                messages: 3,
+                per: Duration::from_secs(10),
+            }),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        // 不用login，避免同步命令消耗令牌
+        let mut bob = connect(&state, addr(2));
+        bob.system().await;
+        bob.send("bob").await;
+        for n in 1..=5 {
+            bob.send(&n.to_string()).await;
+        }
+        let mut received = Vec::new();
+        for _ in 0..5 {
+            received.push(match bob.recv().await {
+                Message::System(reply) => reply,
+                msg => text(&msg).unwrap().2.to_string(),
+            });
+        }
+        assert_eq!(received, vec!["1", "2", "3", "slow down", "slow down"]);
+
+        time::sleep(Duration::from_secs(10)).await;
+        bob.send("6").await;
+        assert_eq!(text(&bob.recv().await), Some((4, "bob", "6")));
+        assert!(matches!(alice.recv().await, Message::Join(_)));
+        for expected in ["1", "2", "3", "6"] {
+            assert_eq!(text(&alice.recv().await).unwrap().2, expected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_violations_mute_the_peer() {
+        let state = state(ChatConfig {
+            rate_limit: Some(RateLimit {
+                messages: 1,
+                per: Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let mut bob = connect(&state, addr(2));
+        bob.system().await;
+        bob.send("bob").await;
+        for n in 0..=MUTE_AFTER_VIOLATIONS {
+            bob.send(&n.to_string()).await;
+        }
+        for _ in 1..=MUTE_AFTER_VIOLATIONS {
+            bob.recv().await;
+        }
+        assert_eq!(
+            bob.system().await,
+            format!("too many messages, muted for {}s", MUTE_DURATION.as_secs())
+        );
+        assert_eq!(
+            bob.command("hi").await,
+            format!("muted for {}s", MUTE_DURATION.as_secs())
+        );
+    }
+
+    #[tokio::test]
+    async fn join_sends_a_signed_webhook() {
+        use axum::{body::Bytes, routing::post, Router};
+        use http::HeaderMap;
+
+        let (tx, mut rx) = channel::<(HeaderMap, Bytes)>(8);
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                tx.send((headers, body)).await.unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = state(ChatConfig {
+            webhook: Some(WebhookConfig {
+                url,
+                secret: Some("shh".to_string()),
+                messages: false,
+            }),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        alice.send("not sent").await;
+        alice.sync().await;
+
+        let (headers, body) = time::timeout(RECV_TIMEOUT, rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"event": "join", "user": "alice", "room": "lobby"})
+        );
+        assert_eq!(
+            headers[WEBHOOK_SIGNATURE_HEADER].to_str().unwrap(),
+            sign_webhook("shh", &body)
+        );
+        // 未开启messages时聊天消息不发送webhook
+        drop(alice);
+        let (_, body) = time::timeout(RECV_TIMEOUT, rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains(r#""event":"leave""#));
+    }
+
+    #[tokio::test]
+    async fn transcript_is_written_and_flushed_on_shutdown() {
+        let dir = temp_dir("transcript");
+        let state = state(ChatConfig {
+            transcript_dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        for line in ["one", "two", "three"] {
+            alice.send(line).await;
+        }
+        alice.sync().await;
+        state.shutdown().await;
+
+        let path = dir.join(format!("chat-{}.log", utc_date(unix_secs())));
+        let contents: Vec<String> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(entry["room"], "lobby");
+                entry["message"]["data"]["content"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(contents, vec!["one", "two", "three"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn utc_date_converts_unix_seconds() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_735_689_599), "2024-12-31");
+    }
+
+    #[tokio::test]
+    async fn reserved_and_blocked_names_are_rejected() {
+        let mut config = ChatConfig {
+            roles: roles(),
+            ..Default::default()
+        };
+        config.blocked_names.insert("spammer".to_string());
+        let state = state(config);
+        let mut client = connect(&state, addr(1));
+        client.system().await;
+        // 角色持有者的大小写变体和徽章名同样视为冒充
+        for name in ["admin", "Admin", "SPAMMER", "Mia", "mod"] {
+            client.send(name).await;
+            assert_eq!(
+                client.system().await,
+                "Name is reserved or not allowed, choose another:",
+                "{}",
+                name
+            );
+        }
+        client.send("alice").await;
+        client.sync().await;
+        assert_eq!(
+            client.command("/nick spammer").await,
+            "name not allowed: spammer"
+        );
+        assert_eq!(
+            client.command("/nick mia").await,
+            "name reserved, reconnect to log in as mia"
+        );
+    }
+
+    #[tokio::test]
+    async fn role_name_requires_its_password() {
+        let state = state(ChatConfig {
+            roles: roles(),
+            ..Default::default()
+        });
+        let mut client = connect(&state, addr(1));
+        client.system().await;
+        for _ in 1..MAX_PASSWORD_ATTEMPTS {
+            client.send("mia").await;
+            client.system().await;
+            client.send("guess").await;
+            assert_eq!(
+                client.system().await,
+                "Wrong password, choose another name:"
+            );
+        }
+        client.send("mia").await;
+        client.system().await;
+        client.send("guess").await;
+        let received = client.closed().await;
+        assert!(matches!(received.as_slice(), [Message::System(m)] if m == "Wrong password"));
+        assert!(state.roster.read().await.is_empty());
+
+        let _mia = login_role(&state, "mia", "secret").await;
+        assert_eq!(*state.roster.read().await, vec!["mia"]);
+    }
+
+    #[tokio::test]
+    async fn queue_depth_grows_for_a_stalled_peer() {
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let (_client, server) = duplex(64);
+        let slow = state.add_peer(
+            addr(7),
+            "slow".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        let depth = || state.peers.get(&slow.id).unwrap().queue_depth();
+
+        flood(&state, 10).await;
+        let before = depth();
+        flood(&state, 10).await;
+        assert!(depth() > before, "{} -> {}", before, depth());
+
+        let mut admin = login(&state, "root").await;
+        admin.command("/auth token").await;
+        let reply = admin.command("/whois slow").await;
+        let expected = format!(
+            "slow {} room=lobby queued={}/{}",
+            addr(7),
+            depth(),
+            MSG_SIZE
+        );
+        assert_eq!(reply, expected);
+    }
+
+    #[tokio::test]
+    async fn dnd_queues_direct_messages_until_cleared() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        assert_eq!(
+            bob.command("/dnd").await,
+            "do-not-disturb on, direct messages will be queued"
+        );
+        assert_eq!(
+            alice.command("/msg bob are you there?").await,
+            "bob is in do-not-disturb, message queued"
+        );
+        assert_eq!(
+            bob.command("/dnd").await,
+            "already in do-not-disturb, 1 queued"
+        );
+
+        bob.send("/dnd off").await;
+        assert!(matches!(
+            bob.recv().await,
+            Message::Direct { from, content, .. } if from == "alice" && content == "are you there?"
+        ));
+        assert_eq!(
+            bob.system().await,
+            "do-not-disturb off, 1 queued message(s) delivered"
+        );
+        assert_eq!(bob.command("/dnd off").await, "not in do-not-disturb");
+
+        alice.send("/msg bob hi").await;
+        assert!(matches!(alice.recv().await, Message::Direct { .. }));
+        assert!(matches!(bob.recv().await, Message::Direct { content, .. } if content == "hi"));
+        assert_eq!(
+            alice.command("/msg nobody hi").await,
+            "no such user: nobody"
+        );
+    }
+
+    #[test]
+    fn format_size_uses_binary_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(format_size(3 << 40), "3072.0 GB");
+    }
+
+    #[tokio::test]
+    async fn share_includes_type_and_size_from_a_head_request() {
+        use axum::{http::header::CONTENT_TYPE, routing::get, Router};
+
+        let app = Router::new().route(
+            "/report.pdf",
+            get(|| async { ([(CONTENT_TYPE, "application/pdf; qs=1")], vec![0u8; 1536]) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/report.pdf", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = state(ChatConfig {
+            share_head: true,
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.sync().await;
+        alice.send(&format!("/share {} Q3 report", url)).await;
+        let expected = format!("alice shared {} (application/pdf, 1.5 KB) - Q3 report", url);
+        assert_eq!(bob.system().await, expected);
+        assert_eq!(alice.system().await, expected);
+
+        // 目标无法访问时只分享链接
+        alice.send("/share http://127.0.0.1:1/gone").await;
+        assert_eq!(bob.system().await, "alice shared http://127.0.0.1:1/gone");
+
+        // 未开启时不请求目标地址
+        let state = self::state(ChatConfig::default());
+        let mut carol = login(&state, "carol").await;
+        carol.send(&format!("/share {}", url)).await;
+        assert_eq!(carol.system().await, format!("carol shared {}", url));
+        assert_eq!(
+            carol.command("/share ftp://example.com/a").await,
+            "usage: /share <http(s) url> [description]"
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_a_full_queue_is_dropped_without_waiting() {
+        let state = state(ChatConfig::default());
+        let (_client, server) = duplex(64);
+        let slow = state.add_peer(
+            addr(7),
+            "slow".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        // 等写任务阻塞在管道上之后再填满发送队列
+        flood(&state, MSG_SIZE).await;
+        time::sleep(Duration::from_millis(50)).await;
+        flood(&state, MSG_SIZE).await;
+
+        let mut alice = login(&state, "alice").await;
+        assert_eq!(
+            alice.command("/msg slow hi").await,
+            "slow's queue is full, message dropped"
+        );
+        assert!(state.peers.contains_key(&slow.id));
+    }
+
+    #[tokio::test]
+    async fn kick_does_not_wait_on_a_stalled_peer() {
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let (_client, server) = duplex(64);
+        let _slow = state.add_peer(
+            addr(7),
+            "slow".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        flood(&state, MSG_SIZE).await;
+        time::sleep(Duration::from_millis(50)).await;
+        flood(&state, MSG_SIZE).await;
+
+        let mut admin = login(&state, "root").await;
+        admin.command("/auth token").await;
+        assert_eq!(admin.command("/kick slow").await, "kicked slow");
+    }
+
+    #[tokio::test]
+    async fn logout_others_does_not_wait_on_a_stalled_session() {
+        let state = state(ChatConfig {
+            unique_names: false,
+            ..Default::default()
+        });
+        let (_client, server) = duplex(64);
+        let _stalled = state.add_peer(
+            addr(7),
+            "alice".into(),
+            Framed::new(server, LinesCodec::new()),
+        );
+        flood(&state, MSG_SIZE).await;
+        time::sleep(Duration::from_millis(50)).await;
+        flood(&state, MSG_SIZE).await;
+
+        let mut alice = login(&state, "alice").await;
+        assert_eq!(
+            alice.command("/logout-others").await,
+            "disconnected 1 other session(s)"
+        );
+    }
+
+    // 发送/resync，返回回放的消息序号和最后的回复
+    async fn resync(client: &mut Client, after: &str) -> (Vec<u64>, String) {
+        client.send(&format!("/resync {}", after)).await;
+        let mut seqs = Vec::new();
+        loop {
+            match client.recv().await {
+                Message::System(reply) => return (seqs, reply),
+                msg => seqs.push(msg.seq().unwrap()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn resync_replays_only_messages_after_the_given_seq() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        for i in 1..=5 {
+            alice.send(&format!("m{}", i)).await;
+        }
+        alice.sync().await;
+
+        assert_eq!(
+            resync(&mut alice, "3").await,
+            (vec![4, 5], "resynced 2 message(s)".to_string())
+        );
+        assert_eq!(
+            resync(&mut alice, "5").await,
+            (vec![], "resynced 0 message(s)".to_string())
+        );
+        assert_eq!(alice.command("/resync x").await, "usage: /resync <seq>");
+
+        // 超出历史容量后，更早的消息无法补齐
+        for i in 0..HISTORY_SIZE {
+            alice.send(&format!("n{}", i)).await;
+        }
+        alice.sync().await;
+        let (seqs, reply) = resync(&mut alice, "3").await;
+        assert_eq!(seqs, (6..=55).collect::<Vec<u64>>());
+        assert_eq!(
+            reply,
+            "messages up to #5 are no longer available, resynced 50 message(s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_say_reaches_only_the_named_room() {
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let mut bob = login(&state, "bob").await;
+        assert_eq!(bob.command("/join dev").await, "joined dev");
+        let mut carol = login(&state, "carol").await;
+        let mut root = login(&state, "root").await;
+        carol.sync().await;
+        root.command("/auth token").await;
+
+        assert_eq!(carol.command("/say dev hi").await, "permission denied");
+        assert_eq!(root.command("/say dev heads up").await, "sent to dev");
+        assert_eq!(bob.system().await, "heads up");
+        assert_eq!(
+            root.command("/say nowhere hi").await,
+            "no such room: nowhere"
+        );
+        assert_eq!(root.command("/say dev").await, "usage: /say <room> <text>");
+        assert!(carol.sync().await.is_empty());
+        assert!(bob.sync().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_room_moves_members_to_the_new_name() {
+        let state = state(ChatConfig {
+            admin_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let mut bob = login(&state, "bob").await;
+        assert_eq!(bob.command("/join dev").await, "joined dev");
+        let mut carol = login(&state, "carol").await;
+        assert_eq!(carol.command("/join dev").await, "joined dev");
+        bob.sync().await;
+        let mut root = login(&state, "root").await;
+        root.command("/auth token").await;
+
+        assert_eq!(
+            bob.command("/rename-room dev ops").await,
+            "permission denied"
+        );
+        assert_eq!(
+            root.command("/rename-room dev ops").await,
+            "renamed dev to ops"
+        );
+        assert_eq!(bob.system().await, "room dev renamed to ops");
+        assert_eq!(carol.system().await, "room dev renamed to ops");
+        assert!(state.room("dev").is_none());
+        assert_eq!(state.room("ops").unwrap().members.len(), 2);
+
+        // 成员继续在新名称的房间里聊天
+        bob.send("hi").await;
+        assert!(matches!(carol.recv().await, Message::Text { content, .. } if content == "hi"));
+        assert_eq!(carol.command("/join ops").await, "already in ops");
+
+        assert_eq!(
+            root.command("/rename-room dev x").await,
+            "no such room: dev"
+        );
+        assert_eq!(
+            root.command("/rename-room ops lobby").await,
+            "can't rename lobby"
+        );
+        root.command("/join qa").await;
+        assert_eq!(
+            root.command("/rename-room ops qa").await,
+            "room qa already exists"
+        );
+        assert_eq!(state.room("ops").unwrap().members.len(), 2);
+        assert_eq!(
+            root.command("/rename-room ops").await,
+            "usage: /rename-room <old> <new>"
+        );
+    }
+
+    #[tokio::test]
+    async fn old_transcripts_are_compressed_and_pruned() {
+        let dir = temp_dir("transcript-gz");
+        std::fs::write(dir.join("chat-2000-01-01.log"), "old line\n").unwrap();
+        for day in ["1999-12-30", "1999-12-31"] {
+            std::fs::write(dir.join(format!("chat-{}.log.gz", day)), "").unwrap();
+        }
+        let state = state(ChatConfig {
+            transcript_dir: Some(dir.clone()),
+            transcript_keep: Some(2),
+            ..Default::default()
+        });
+        let mut alice = login(&state, "alice").await;
+        alice.send("new line").await;
+        alice.sync().await;
+        state.shutdown().await;
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let today = format!("chat-{}.log", utc_date(unix_secs()));
+        assert_eq!(
+            names,
+            vec![
+                "chat-1999-12-31.log.gz".to_string(),
+                "chat-2000-01-01.log.gz".to_string(),
+                today.clone(),
+            ]
+        );
+        let mut old = String::new();
+        let gz = std::fs::File::open(dir.join("chat-2000-01-01.log.gz")).unwrap();
+        flate2::read::GzDecoder::new(gz)
+            .read_to_string(&mut old)
+            .unwrap();
+        assert_eq!(old, "old line\n");
+        assert!(std::fs::read_to_string(dir.join(today))
+            .unwrap()
+            .contains("new line"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn msg_with_receipt_confirms_delivery_to_the_sender() {
+        let state = state(ChatConfig::default());
+        let mut alice = login(&state, "alice").await;
+        let mut bob = login(&state, "bob").await;
+        alice.recv().await;
+
+        alice.send("/msg -r bob hi").await;
+        assert!(matches!(alice.recv().await, Message::Direct { content, .. } if content == "hi"));
+        assert_eq!(alice.system().await, "[delivered to bob]");
+        assert!(matches!(bob.recv().await, Message::Direct { content, .. } if content == "hi"));
+
+        // 勿扰期间排队的私信在投递时才发送回执
+        bob.command("/dnd").await;
+        assert_eq!(
+            alice.command("/msg -r bob later").await,
+            "bob is in do-not-disturb, message queued"
+        );
+        assert!(alice.sync().await.is_empty());
+        bob.send("/dnd off").await;
+        assert!(matches!(bob.recv().await, Message::Direct { content, .. } if content == "later"));
+        bob.system().await;
+        assert_eq!(alice.system().await, "[delivered to bob]");
+
+        assert_eq!(
+            alice.command("/msg -r bob").await,
+            "usage: /msg [-r] <username> <text>"
+        );
+    }
+}